@@ -1,18 +1,38 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    coins, from_binary, to_binary, Addr, BankMsg, Binary, CosmosMsg, Decimal, Deps, DepsMut, Env,
-    MessageInfo, Order, Response, StdResult, WasmMsg,
+    coin, coins, from_binary, to_binary, Addr, BankMsg, Binary, Coin, CosmosMsg, Decimal, Deps,
+    DepsMut, Env, Event, MessageInfo, Order, Reply, Response, StdResult, Storage, SubMsg,
+    Timestamp, Uint128, WasmMsg,
 };
 use cw2::set_contract_version;
-use cw20::{Cw20ExecuteMsg, Cw20ReceiveMsg};
+use cw20::{BalanceResponse, Cw20ExecuteMsg, Cw20QueryMsg, Cw20ReceiveMsg, TokenInfoResponse};
 
 use crate::error::ContractError;
-use crate::msg::{DepositByIdResponse, ExecuteMsg, InstantiateMsg, QueryMsg, ReceiveCw20Msg};
+use crate::events::OtcEvent;
+use crate::msg::{
+    AntiSpamConfigResponse, AssetRegistryResponse, AssetSolvency, DepositByIdResponse,
+    DepositResponse, ExecuteMsg, FeeShare, FillResponse, InstantiateMsg, QueryMsg, ReceiveCw20Msg,
+    SimulateAcceptResponse, SolvencyResponse, StakeQueryMsg, StakedBalanceAtHeightResponse,
+    StakingConfigMsg, SudoMsg, TradeHistoryFilter, TradeHistoryResponse, VwapResponse,
+};
 use crate::state::{
-    add_deposit, Asset, AssetType, Deposit, FeeConfig, Offer, DEPOSITS, FEE_CONFIG, ID,
+    add_accrued_fee, add_deposit, add_pair_fill, add_referral_reward, add_volume, amount_at_price,
+    fee_tier, fills, get_accrued_fees, get_asset_registry, get_expired_deposits, get_liabilities,
+    get_referral_rewards, is_asset_permitted, remove_deposit, sealed_bid_commitment,
+    volume_seconds, AntiSpamConfig, Asset, AssetListing, AssetRegistryEntry, AssetType, Bid,
+    CancelledOffer, Deposit, DutchAuction, EnglishAuction, FeeConfig, FeeRecipient, Fill, Offer,
+    Pair, PairStats, PauseState, RevealedBid, SealedBid, SealedBidAuction, StakingConfig,
+    TokenMetadata, UnrevealedEscrows, VolumeConfig, ACCRUED_FEES, ADMIN, ALLOWLIST_ONLY,
+    ANTI_SPAM_CONFIG, ASSET_REGISTRY, BOND_CREDITS, CANCELLED_OFFERS, CW20_RESERVES, DEPOSITS,
+    ENGLISH_AUCTIONS, FEE_CONFIG, FEE_WEIGHT_TOTAL, ID, LAST_RESERVE_SYNC_ID, MIN_DEPOSITS,
+    PAIR_STATS, PAUSE_STATE, PENDING_RESERVE_SYNCS, PRICE_OBSERVATIONS, REFERRAL_REWARDS,
+    SEALED_BIDS, SEALED_BID_AUCTIONS, STAKING_CONFIG, TOKEN_METADATA, UNREVEALED_ESCROWS,
+    VOLUME_CONFIG,
 };
 
+use std::collections::{BTreeMap, BTreeSet};
+
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:over-the-counter";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -21,82 +41,672 @@ const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 pub fn instantiate(
     deps: DepsMut,
     _env: Env,
-    _info: MessageInfo,
+    info: MessageInfo,
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
+    // Nothing accounts for coins sent on instantiation, they would be stuck in the contract
+    if !info.funds.is_empty() {
+        return Err(ContractError::NonPayable {});
+    }
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
+    let admin = if let Some(admin) = msg.admin {
+        deps.api.addr_validate(&admin)?
+    } else {
+        info.sender
+    };
+    ADMIN.save(deps.storage, &admin)?;
+    PAUSE_STATE.save(deps.storage, &PauseState::default())?;
+    ALLOWLIST_ONLY.save(deps.storage, &false)?;
+    ANTI_SPAM_CONFIG.save(deps.storage, &AntiSpamConfig::default())?;
+
+    let service_fee = Decimal::percent(1);
+    let referral_share = msg.referral_share.unwrap_or_default();
+    if referral_share > Decimal::percent(50) {
+        return Err(ContractError::InvalidReferralShare {});
+    }
+    let fee_recipients = validate_fee_recipients(deps.as_ref(), msg.fee_recipients)?;
     FEE_CONFIG.save(
         deps.storage,
         &FeeConfig {
-            fee_address: deps.api.addr_validate(&msg.fee_address)?,
-            service_fee: Decimal::percent(1),
+            fee_recipients,
+            service_fee,
+            referral_share,
         },
     )?;
 
+    if let Some(volume_config) = msg.volume_config {
+        validate_volume_config(&volume_config, service_fee)?;
+        VOLUME_CONFIG.save(deps.storage, &volume_config)?;
+    }
+
+    if let Some(staking_config) = msg.staking_config {
+        let staking_config = validate_staking_config(deps.as_ref(), staking_config)?;
+        STAKING_CONFIG.save(deps.storage, &staking_config)?;
+    }
+
     Ok(Response::new()
         .add_attribute("instantiate", "over-the-counter")
-        .add_attribute("fee-address", msg.fee_address))
+        .add_attribute("admin", admin.to_string()))
+}
+
+fn validate_fee_recipients(
+    deps: Deps,
+    shares: Vec<FeeShare>,
+) -> Result<Vec<FeeRecipient>, ContractError> {
+    let recipients = shares
+        .into_iter()
+        .map(|share| {
+            Ok(FeeRecipient {
+                address: deps.api.addr_validate(&share.address)?,
+                weight: share.weight,
+            })
+        })
+        .collect::<StdResult<Vec<FeeRecipient>>>()?;
+
+    let total_weight: u32 = recipients.iter().map(|r| r.weight as u32).sum();
+    let unique = recipients.iter().enumerate().all(|(i, r)| {
+        !recipients[..i]
+            .iter()
+            .any(|other| other.address == r.address)
+    });
+    if total_weight != FEE_WEIGHT_TOTAL as u32
+        || !unique
+        || recipients.iter().any(|r| r.weight == 0)
+    {
+        return Err(ContractError::InvalidFeeRecipients {});
+    }
+    Ok(recipients)
+}
+
+fn validate_volume_config(
+    config: &VolumeConfig,
+    service_fee: Decimal,
+) -> Result<(), ContractError> {
+    let sorted = config
+        .tiers
+        .windows(2)
+        .all(|pair| pair[0].min_volume < pair[1].min_volume);
+    let valid_fees = service_fee < Decimal::one()
+        && config
+            .tiers
+            .iter()
+            .all(|tier| tier.service_fee < Decimal::one());
+    if !sorted || !valid_fees {
+        return Err(ContractError::InvalidFeeTiers {});
+    }
+    Ok(())
+}
+
+fn validate_staking_config(
+    deps: Deps,
+    config: StakingConfigMsg,
+) -> Result<StakingConfig, ContractError> {
+    let sorted = config
+        .discounts
+        .windows(2)
+        .all(|pair| pair[0].min_stake < pair[1].min_stake);
+    let valid_discounts = config
+        .discounts
+        .iter()
+        .all(|discount| discount.discount <= Decimal::one());
+    if !sorted || !valid_discounts {
+        return Err(ContractError::InvalidStakingDiscounts {});
+    }
+    Ok(StakingConfig {
+        staking_contract: deps.api.addr_validate(&config.staking_contract)?,
+        discounts: config.discounts,
+    })
+}
+
+/// Returns part of the service fee waived for address, based on its staked balance
+fn staking_discount(deps: Deps, address: &Addr) -> StdResult<Decimal> {
+    let config = match STAKING_CONFIG.may_load(deps.storage)? {
+        Some(config) => config,
+        None => return Ok(Decimal::zero()),
+    };
+
+    // Misbehaving staking contract should not block exchanges, it only means no discount
+    let staked = deps
+        .querier
+        .query_wasm_smart::<StakedBalanceAtHeightResponse>(
+            &config.staking_contract,
+            &StakeQueryMsg::StakedBalanceAtHeight {
+                address: address.to_string(),
+                height: None,
+            },
+        )
+        .map(|response| response.balance)
+        .unwrap_or_default();
+
+    Ok(config
+        .discounts
+        .into_iter()
+        .rev()
+        .find(|discount| discount.min_stake <= staked)
+        .map(|discount| discount.discount)
+        .unwrap_or_default())
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn execute(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response, ContractError> {
+    #[cfg(all(feature = "solvency-check", debug_assertions))]
+    let contract = env.contract.address.clone();
+    let response = dispatch_execute(deps.branch(), env, info, msg)?;
+    let response = record_cw20_outflows(deps.storage, response)?;
+    #[cfg(all(feature = "solvency-check", debug_assertions))]
+    assert_solvency(deps.as_ref(), &contract, &response);
+    Ok(response)
+}
+
+/// Keeps reserves of balance delta accounted tokens in line with transfers leaving the contract
+/// Token may also tax transfers sent by the contract, so the last transfer of each such token
+/// replies to set its reserve to the balance actually left once the transfers are done
+/// Each reply gets its own ID mapped to the token, so only that token's balance is queried
+fn record_cw20_outflows(storage: &mut dyn Storage, mut response: Response) -> StdResult<Response> {
+    let mut last_transfers: BTreeMap<Addr, usize> = BTreeMap::new();
+    for (i, sub_msg) in response.messages.iter().enumerate() {
+        if let CosmosMsg::Wasm(WasmMsg::Execute {
+            contract_addr, msg, ..
+        }) = &sub_msg.msg
+        {
+            let token = Addr::unchecked(contract_addr);
+            if let Some(reserve) = CW20_RESERVES.may_load(storage, &token)? {
+                if let Ok(Cw20ExecuteMsg::Transfer { amount, .. }) = from_binary(msg) {
+                    CW20_RESERVES.save(storage, &token, &reserve.saturating_sub(amount))?;
+                    last_transfers.insert(token, i);
+                }
+            }
+        }
+    }
+    if last_transfers.is_empty() {
+        return Ok(response);
+    }
+    let mut reply_id = LAST_RESERVE_SYNC_ID.may_load(storage)?.unwrap_or_default();
+    for (token, i) in last_transfers {
+        reply_id += 1;
+        PENDING_RESERVE_SYNCS.save(storage, reply_id, &token)?;
+        let msg = response.messages[i].msg.clone();
+        response.messages[i] = SubMsg::reply_on_success(msg, reply_id);
+    }
+    LAST_RESERVE_SYNC_ID.save(storage, &reply_id)?;
+    Ok(response)
+}
+
+/// Panics if balances left after the response's transfers don't cover the contract's liabilities
+#[cfg(all(feature = "solvency-check", debug_assertions))]
+fn assert_solvency(deps: Deps, contract: &Addr, response: &Response) {
+    let mut outflows: Vec<Asset> = vec![];
+    for sub_msg in &response.messages {
+        match &sub_msg.msg {
+            CosmosMsg::Bank(BankMsg::Send { amount, .. }) => outflows.extend(
+                amount
+                    .iter()
+                    .map(|coin| Asset::new_native(coin.amount.u128(), &coin.denom)),
+            ),
+            CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr, msg, ..
+            }) => {
+                if let Ok(Cw20ExecuteMsg::Transfer { amount, .. }) = from_binary(msg) {
+                    outflows.push(Asset::new_cw20(amount.u128(), contract_addr));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let solvency = query::solvency(deps, contract).unwrap();
+    for asset in solvency.assets {
+        let outflow: Uint128 = outflows
+            .iter()
+            .filter(|outflow| outflow.denom == asset.asset)
+            .map(|outflow| outflow.amount)
+            .sum();
+        assert!(
+            asset.balance.saturating_sub(outflow) >= asset.liabilities,
+            "contract can't cover {} of {}",
+            asset.liabilities,
+            asset.asset
+        );
+    }
+}
+
+fn dispatch_execute(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     info: MessageInfo,
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
+    // Only deposits and acceptances of native tokens consume funds, any coins sent
+    // with other messages would be stuck in the contract
+    if !matches!(
+        msg,
+        ExecuteMsg::Deposit { .. }
+            | ExecuteMsg::DepositAtPrice { .. }
+            | ExecuteMsg::DepositDutchAuction { .. }
+            | ExecuteMsg::AcceptExchange { .. }
+            | ExecuteMsg::Bid { .. }
+            | ExecuteMsg::CommitBid { .. }
+            | ExecuteMsg::PrepayBond {}
+    ) && !info.funds.is_empty()
+    {
+        return Err(ContractError::NonPayable {});
+    }
+
     match msg {
-        ExecuteMsg::Receive(cw20_msg) => receive_cw20(deps, info, cw20_msg),
-        ExecuteMsg::Deposit { exchange, from } => {
-            let funds = info
-                .funds
-                .first()
-                .cloned()
-                .ok_or(ContractError::NoFundsWithDeposit {})?;
+        ExecuteMsg::Receive(cw20_msg) => receive_cw20(deps, env, info, cw20_msg),
+        ExecuteMsg::Deposit {
+            exchange,
+            from,
+            referrer,
+            expires,
+        } => {
+            let (funds, bond) = native_deposit_funds(deps.as_ref(), &info)?;
             execute::deposit(
                 deps,
+                env,
                 info.sender,
-                Asset::new_native(funds.amount.u128(), &funds.denom),
-                exchange,
+                funds,
+                bond,
+                execute::Ask::Fixed(exchange),
                 from,
+                referrer,
+                expires,
             )
         }
-        ExecuteMsg::Withdraw { id } => execute::withdraw(deps, info.sender, id),
-        ExecuteMsg::AcceptExchange { deposit_id } => {
-            let funds = info
-                .funds
-                .first()
-                .cloned()
-                .ok_or(ContractError::NoFundsWithDeposit {})?;
-            execute::accept_exchange(
+        ExecuteMsg::DepositAtPrice {
+            ask,
+            price,
+            from,
+            referrer,
+            expires,
+        } => {
+            let (funds, bond) = native_deposit_funds(deps.as_ref(), &info)?;
+            execute::deposit(
                 deps,
+                env,
                 info.sender,
-                deposit_id,
-                Asset::new_native(funds.amount.u128(), &funds.denom),
+                funds,
+                bond,
+                execute::Ask::Price { denom: ask, price },
+                from,
+                referrer,
+                expires,
+            )
+        }
+        ExecuteMsg::DepositDutchAuction {
+            ask,
+            auction,
+            from,
+            referrer,
+            expires,
+        } => {
+            let (funds, bond) = native_deposit_funds(deps.as_ref(), &info)?;
+            execute::deposit(
+                deps,
+                env,
+                info.sender,
+                funds,
+                bond,
+                execute::Ask::DutchAuction {
+                    denom: ask,
+                    auction,
+                },
+                from,
+                referrer,
+                expires,
             )
         }
+        ExecuteMsg::Withdraw { id } => execute::withdraw(deps, info.sender, id),
+        ExecuteMsg::AcceptExchange {
+            deposit_id,
+            referrer,
+        } => {
+            let funds = one_native_asset(&info)?;
+            execute::accept_exchange(deps, env, info.sender, deposit_id, funds, referrer)
+        }
+        ExecuteMsg::OpenAuction {
+            deposit_id,
+            min_increment,
+            end,
+            extension,
+        } => execute::open_auction(
+            deps,
+            env,
+            info.sender,
+            deposit_id,
+            min_increment,
+            end,
+            extension,
+        ),
+        ExecuteMsg::Bid { deposit_id } => {
+            let funds = one_native_asset(&info)?;
+            execute::bid(deps, env, info.sender, deposit_id, funds)
+        }
+        ExecuteMsg::OpenSealedBidAuction {
+            deposit_id,
+            commit_end,
+            reveal_end,
+            penalty,
+        } => execute::open_sealed_bid_auction(
+            deps,
+            env,
+            info.sender,
+            deposit_id,
+            commit_end,
+            reveal_end,
+            penalty,
+        ),
+        ExecuteMsg::CommitBid {
+            deposit_id,
+            commitment,
+        } => {
+            let funds = one_native_asset(&info)?;
+            execute::commit_bid(deps, env, info.sender, deposit_id, commitment, funds)
+        }
+        ExecuteMsg::RevealBid {
+            deposit_id,
+            amount,
+            salt,
+        } => execute::reveal_bid(deps, env, info.sender, deposit_id, amount, salt),
+        ExecuteMsg::SettleAuction { deposit_id } => execute::settle_auction(deps, env, deposit_id),
+        ExecuteMsg::ClaimBidEscrow { deposit_id } => {
+            execute::claim_bid_escrow(deps, info.sender, deposit_id)
+        }
+        ExecuteMsg::ClaimReferralRewards {} => execute::claim_referral_rewards(deps, info.sender),
+        ExecuteMsg::ClaimFees { assets } => execute::claim_fees(deps, info.sender, assets),
+        ExecuteMsg::SetPause {
+            deposits,
+            acceptances,
+            withdrawals,
+        } => execute::set_pause(deps, info.sender, deposits, acceptances, withdrawals),
+        ExecuteMsg::UpdateAssetRegistry {
+            allow,
+            block,
+            remove,
+            allowlist_only,
+        } => {
+            execute::update_asset_registry(deps, info.sender, allow, block, remove, allowlist_only)
+        }
+        ExecuteMsg::SetBalanceDeltaAccounting { token, enabled } => {
+            execute::set_balance_delta_accounting(deps, env, info.sender, token, enabled)
+        }
+        ExecuteMsg::PrepayBond {} => execute::prepay_bond(deps, info),
+        ExecuteMsg::UpdateAntiSpamConfig {
+            max_offers_per_address,
+            offer_bond,
+            keeper_bounty,
+            min_deposits,
+        } => execute::update_anti_spam_config(
+            deps,
+            info.sender,
+            max_offers_per_address,
+            offer_bond,
+            keeper_bounty,
+            min_deposits,
+        ),
+        ExecuteMsg::SweepExpired { limit } => execute::sweep_expired(deps, env, info.sender, limit),
+        ExecuteMsg::EmergencyRefund { limit } => {
+            execute::emergency_refund(deps, env, info.sender, limit)
+        }
     }
 }
 
-pub fn receive_cw20(
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn sudo(mut deps: DepsMut, env: Env, msg: SudoMsg) -> Result<Response, ContractError> {
+    #[cfg(all(feature = "solvency-check", debug_assertions))]
+    let contract = env.contract.address.clone();
+    let response = dispatch_sudo(deps.branch(), env, msg)?;
+    let response = record_cw20_outflows(deps.storage, response)?;
+    #[cfg(all(feature = "solvency-check", debug_assertions))]
+    assert_solvency(deps.as_ref(), &contract, &response);
+    Ok(response)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    let token = PENDING_RESERVE_SYNCS
+        .may_load(deps.storage, msg.id)?
+        .ok_or(ContractError::UnknownReplyId { id: msg.id })?;
+    PENDING_RESERVE_SYNCS.remove(deps.storage, msg.id);
+    // Accounting may have been disabled by the transfer's own execution
+    if CW20_RESERVES.has(deps.storage, &token) {
+        let balance = query_cw20_balance(deps.as_ref(), &token, &env.contract.address)?;
+        CW20_RESERVES.save(deps.storage, &token, &balance)?;
+    }
+    Ok(Response::new())
+}
+
+fn dispatch_sudo(deps: DepsMut, env: Env, msg: SudoMsg) -> Result<Response, ContractError> {
+    match msg {
+        SudoMsg::UpdateFeeConfig {
+            fee_recipients,
+            service_fee,
+            referral_share,
+            volume_config,
+        } => sudo::update_fee_config(
+            deps,
+            fee_recipients,
+            service_fee,
+            referral_share,
+            volume_config,
+        ),
+        SudoMsg::SetPause {
+            deposits,
+            acceptances,
+            withdrawals,
+        } => execute::update_pause(deps.storage, deposits, acceptances, withdrawals),
+        SudoMsg::ForceRefund { address, id } => sudo::force_refund(deps, address, id),
+        SudoMsg::ExpireOffers { limit } => sudo::expire_offers(deps, env, limit),
+    }
+}
+
+/// Extracts the only native coin sent with the message
+fn one_native_asset(info: &MessageInfo) -> Result<Asset, ContractError> {
+    match info.funds.as_slice() {
+        [] => Err(ContractError::NoFundsWithDeposit {}),
+        [coin] if coin.amount.is_zero() => Err(ContractError::ZeroAmount {}),
+        [coin] => Ok(Asset::new_native(coin.amount.u128(), &coin.denom)),
+        _ => Err(ContractError::MultipleDenoms {}),
+    }
+}
+
+/// Separates offer bond, if one is required, from native funds sent with a deposit
+fn native_deposit_funds(
+    deps: Deps,
+    info: &MessageInfo,
+) -> Result<(Asset, Option<Asset>), ContractError> {
+    let bond = match ANTI_SPAM_CONFIG.load(deps.storage)?.offer_bond {
+        Some(bond) => bond,
+        None => return Ok((one_native_asset(info)?, None)),
+    };
+    let bond_not_provided = || ContractError::BondNotProvided {
+        bond: bond.to_string(),
+    };
+
+    let deposit = match info.funds.as_slice() {
+        // Bond included in the deposit of the same denom
+        [coin] if coin.denom == bond.denom => {
+            if coin.amount <= bond.amount {
+                return Err(bond_not_provided());
+            }
+            Asset::new_native((coin.amount - bond.amount).u128(), &coin.denom)
+        }
+        [first, second] => {
+            let (deposit, sent_bond) = if first.denom == bond.denom {
+                (second, first)
+            } else {
+                (first, second)
+            };
+            if *sent_bond != bond {
+                return Err(bond_not_provided());
+            }
+            if deposit.amount.is_zero() {
+                return Err(ContractError::ZeroAmount {});
+            }
+            Asset::new_native(deposit.amount.u128(), &deposit.denom)
+        }
+        [_] => return Err(bond_not_provided()),
+        [] => return Err(ContractError::NoFundsWithDeposit {}),
+        _ => return Err(ContractError::MultipleDenoms {}),
+    };
+    Ok((
+        deposit,
+        Some(Asset::new_native(bond.amount.u128(), &bond.denom)),
+    ))
+}
+
+/// Takes offer bond, if one is required, from bonds prepaid by depositor
+fn prepaid_bond(deps: DepsMut, depositor: &Addr) -> Result<Option<Asset>, ContractError> {
+    let bond = match ANTI_SPAM_CONFIG.load(deps.storage)?.offer_bond {
+        Some(bond) => bond,
+        None => return Ok(None),
+    };
+    let mut credit = BOND_CREDITS
+        .may_load(deps.storage, depositor)?
+        .filter(|credit| credit.denom == bond.denom && credit.amount >= bond.amount)
+        .ok_or_else(|| ContractError::BondNotProvided {
+            bond: bond.to_string(),
+        })?;
+    credit.amount -= bond.amount;
+    BOND_CREDITS.save(deps.storage, depositor, &credit)?;
+    Ok(Some(Asset::new_native(bond.amount.u128(), &bond.denom)))
+}
+
+fn query_cw20_balance(deps: Deps, token: &Addr, address: &Addr) -> StdResult<Uint128> {
+    let response: BalanceResponse = deps.querier.query_wasm_smart(
+        token,
+        &Cw20QueryMsg::Balance {
+            address: address.to_string(),
+        },
+    )?;
+    Ok(response.balance)
+}
+
+/// Returns amount of cw20 tokens actually received by the contract
+/// For balance delta accounted tokens it's the change of contract's balance,
+/// capped by the amount declared by the token
+fn received_cw20_amount(
     deps: DepsMut,
+    env: &Env,
+    token: &Addr,
+    declared: Uint128,
+) -> StdResult<Uint128> {
+    let reserve = match CW20_RESERVES.may_load(deps.storage, token)? {
+        Some(reserve) => reserve,
+        None => return Ok(declared),
+    };
+    let balance = query_cw20_balance(deps.as_ref(), token, &env.contract.address)?;
+    CW20_RESERVES.save(deps.storage, token, &balance)?;
+    Ok(balance.saturating_sub(reserve).min(declared))
+}
+
+pub fn receive_cw20(
+    mut deps: DepsMut,
+    env: Env,
     info: MessageInfo,
-    cw20_msg: Cw20ReceiveMsg,
+    mut cw20_msg: Cw20ReceiveMsg,
 ) -> Result<Response, ContractError> {
+    cw20_msg.amount = received_cw20_amount(deps.branch(), &env, &info.sender, cw20_msg.amount)?;
+    if cw20_msg.amount.is_zero() {
+        return Err(ContractError::ZeroAmount {});
+    }
     let sender = deps.api.addr_validate(&cw20_msg.sender)?;
     match from_binary(&cw20_msg.msg)? {
-        ReceiveCw20Msg::Deposit { exchange, from } => execute::deposit(
+        ReceiveCw20Msg::Deposit {
+            exchange,
+            from,
+            referrer,
+            expires,
+        } => {
+            let bond = prepaid_bond(deps.branch(), &sender)?;
+            execute::deposit(
+                deps,
+                env,
+                sender,
+                Asset::new_cw20(cw20_msg.amount.u128(), info.sender.as_str()),
+                bond,
+                execute::Ask::Fixed(exchange),
+                from,
+                referrer,
+                expires,
+            )
+        }
+        ReceiveCw20Msg::DepositAtPrice {
+            ask,
+            price,
+            from,
+            referrer,
+            expires,
+        } => {
+            let bond = prepaid_bond(deps.branch(), &sender)?;
+            execute::deposit(
+                deps,
+                env,
+                sender,
+                Asset::new_cw20(cw20_msg.amount.u128(), info.sender.as_str()),
+                bond,
+                execute::Ask::Price { denom: ask, price },
+                from,
+                referrer,
+                expires,
+            )
+        }
+        ReceiveCw20Msg::DepositDutchAuction {
+            ask,
+            auction,
+            from,
+            referrer,
+            expires,
+        } => {
+            let bond = prepaid_bond(deps.branch(), &sender)?;
+            execute::deposit(
+                deps,
+                env,
+                sender,
+                Asset::new_cw20(cw20_msg.amount.u128(), info.sender.as_str()),
+                bond,
+                execute::Ask::DutchAuction {
+                    denom: ask,
+                    auction,
+                },
+                from,
+                referrer,
+                expires,
+            )
+        }
+        ReceiveCw20Msg::AcceptExchange {
+            deposit_id,
+            referrer,
+        } => execute::accept_exchange(
+            deps,
+            env,
+            sender,
+            deposit_id,
+            Asset::new_cw20(cw20_msg.amount.u128(), info.sender.as_str()),
+            referrer,
+        ),
+        ReceiveCw20Msg::Bid { deposit_id } => execute::bid(
             deps,
+            env,
             sender,
+            deposit_id,
             Asset::new_cw20(cw20_msg.amount.u128(), info.sender.as_str()),
-            exchange,
-            from,
         ),
-        ReceiveCw20Msg::AcceptExchange { deposit_id } => execute::accept_exchange(
+        ReceiveCw20Msg::CommitBid {
+            deposit_id,
+            commitment,
+        } => execute::commit_bid(
             deps,
+            env,
             sender,
             deposit_id,
+            commitment,
             Asset::new_cw20(cw20_msg.amount.u128(), info.sender.as_str()),
         ),
     }
@@ -105,18 +715,174 @@ pub fn receive_cw20(
 mod execute {
     use super::*;
 
+    const MAX_SWEEP_LIMIT: u32 = 30;
+    const DEFAULT_SWEEP_LIMIT: u32 = 10;
+    /// Lowest penalty of sealed bids in percent, so that committing bids with no intention
+    /// of revealing them isn't free
+    const MIN_SEALED_BID_PENALTY_PERCENT: u64 = 1;
+    /// Longest extension of an auction by a late bid, one week
+    const MAX_AUCTION_EXTENSION: u64 = 7 * 24 * 60 * 60;
+
+    fn ensure_admin(deps: Deps, sender: &Addr) -> Result<(), ContractError> {
+        if ADMIN.load(deps.storage)? != *sender {
+            return Err(ContractError::Unauthorized {});
+        }
+        Ok(())
+    }
+
+    fn ensure_not_paused(
+        deps: Deps,
+        paused: impl Fn(&PauseState) -> bool,
+        operation: &str,
+    ) -> Result<(), ContractError> {
+        if paused(&PAUSE_STATE.load(deps.storage)?) {
+            return Err(ContractError::Paused {
+                operation: operation.to_owned(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that address is a cw20 contract by querying its token info,
+    /// caching the metadata on first use
+    fn validate_cw20(deps: DepsMut, address: &str) -> Result<Addr, ContractError> {
+        let address = deps.api.addr_validate(address)?;
+        if TOKEN_METADATA.has(deps.storage, &address) {
+            return Ok(address);
+        }
+
+        let token_info: TokenInfoResponse = deps
+            .querier
+            .query_wasm_smart(&address, &Cw20QueryMsg::TokenInfo {})
+            .map_err(|_| ContractError::InvalidCw20 {
+                address: address.to_string(),
+            })?;
+        TOKEN_METADATA.save(
+            deps.storage,
+            &address,
+            &TokenMetadata {
+                name: token_info.name,
+                symbol: token_info.symbol,
+                decimals: token_info.decimals,
+            },
+        )?;
+        Ok(address)
+    }
+
+    fn ensure_permitted(deps: Deps, assets: &[&AssetType]) -> Result<(), ContractError> {
+        for asset in assets {
+            if !is_asset_permitted(deps.storage, asset)? {
+                return Err(ContractError::AssetNotPermitted {
+                    asset: asset.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_referrer(
+        deps: Deps,
+        sender: &Addr,
+        referrer: Option<String>,
+    ) -> Result<Option<Addr>, ContractError> {
+        let referrer = if let Some(referrer) = referrer {
+            Some(deps.api.addr_validate(&referrer)?)
+        } else {
+            None
+        };
+        if referrer.as_ref() == Some(sender) {
+            return Err(ContractError::SelfReferral {});
+        }
+        Ok(referrer)
+    }
+
+    /// What the depositor asks for in exchange
+    pub enum Ask {
+        Fixed(Asset),
+        Price {
+            denom: AssetType,
+            price: Decimal,
+        },
+        DutchAuction {
+            denom: AssetType,
+            auction: DutchAuction,
+        },
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn deposit(
-        deps: DepsMut,
+        mut deps: DepsMut,
+        env: Env,
         sender: Addr,
         deposit: Asset,
-        exchange: Asset,
+        bond: Option<Asset>,
+        ask: Ask,
         from: Option<String>,
+        referrer: Option<String>,
+        expires: Option<Timestamp>,
     ) -> Result<Response, ContractError> {
+        ensure_not_paused(deps.as_ref(), |pause| pause.deposits, "Deposits")?;
+
+        // Priced offers keep amount required for the whole deposit at the highest price
+        let (exchange, price, dutch_auction) = match ask {
+            Ask::Fixed(exchange) => (exchange, None, None),
+            Ask::Price { denom, price } => {
+                if price.is_zero() {
+                    return Err(ContractError::InvalidPrice {});
+                }
+                let exchange = Asset {
+                    denom,
+                    amount: amount_at_price(deposit.amount, price)?,
+                };
+                (exchange, Some(price), None)
+            }
+            Ask::DutchAuction { denom, auction } => {
+                if !auction.is_valid() {
+                    return Err(ContractError::InvalidDutchAuction {});
+                }
+                let exchange = Asset {
+                    denom,
+                    amount: amount_at_price(deposit.amount, auction.start_price)?,
+                };
+                (exchange, None, Some(auction))
+            }
+        };
+        ensure_permitted(deps.as_ref(), &[&deposit.denom, &exchange.denom])?;
+
+        if matches!(expires, Some(expires) if expires <= env.block.time) {
+            return Err(ContractError::InvalidExpiration {});
+        }
+
+        if let Some(min) = MIN_DEPOSITS.may_load(deps.storage, &deposit.denom.key())? {
+            if deposit.amount < min.amount {
+                return Err(ContractError::DepositTooSmall {
+                    min: min.to_string(),
+                });
+            }
+        }
+        if let Some(max) = ANTI_SPAM_CONFIG.load(deps.storage)?.max_offers_per_address {
+            let open_offers = DEPOSITS
+                .prefix(&sender)
+                .keys(deps.storage, None, None, Order::Ascending)
+                .take(max as usize)
+                .count();
+            if open_offers >= max as usize {
+                return Err(ContractError::TooManyOffers { max });
+            }
+        }
+
+        for asset in [&deposit.denom, &exchange.denom] {
+            if let AssetType::Cw20(address) = asset {
+                validate_cw20(deps.branch(), address)?;
+            }
+        }
+
         let from = if let Some(from) = from {
             Some(deps.api.addr_validate(&from)?)
         } else {
             None
         };
+        let referrer = validate_referrer(deps.as_ref(), &sender, referrer)?;
 
         let response = Response::new()
             .add_attribute("execute", "deposit")
@@ -126,204 +892,1751 @@ mod execute {
 
         let offer = Deposit {
             deposit,
-            offer: Offer { exchange, from },
+            offer: Offer {
+                exchange,
+                from,
+                expires,
+                price,
+                dutch_auction,
+            },
+            referrer,
+            bond,
         };
 
-        add_deposit(deps.storage, &sender, &offer)?;
+        let id = add_deposit(deps.storage, &sender, &offer)?;
 
-        Ok(response)
+        Ok(response
+            .add_attribute("id", id.to_string())
+            .add_event(Event::from(OtcEvent::Deposit {
+                id,
+                maker: sender,
+                deposit: offer.deposit,
+                exchange: offer.offer.exchange,
+            }))
+            .set_data(to_binary(&DepositResponse { id })?))
     }
 
     pub fn withdraw(
         deps: DepsMut,
         sender: Addr,
         deposit_id: Option<ID>,
+    ) -> Result<Response, ContractError> {
+        ensure_not_paused(deps.as_ref(), |pause| pause.withdrawals, "Withdrawals")?;
+
+        // Deposits with bids on them are bound to be settled
+        let ids = match deposit_id {
+            Some(id) => vec![id],
+            None => DEPOSITS
+                .prefix(&sender)
+                .keys(deps.storage, None, None, Order::Ascending)
+                .collect::<StdResult<Vec<ID>>>()?,
+        };
+        for id in ids {
+            if matches!(
+                ENGLISH_AUCTIONS.may_load(deps.storage, id)?,
+                Some(EnglishAuction {
+                    best_bid: Some(_),
+                    ..
+                })
+            ) || matches!(
+                SEALED_BID_AUCTIONS.may_load(deps.storage, id)?,
+                Some(auction) if auction.best_bid.is_some() || auction.unrevealed > 0
+            ) {
+                return Err(ContractError::AuctionInProgress { id });
+            }
+        }
+
+        let response = refund_deposits(deps.storage, &sender, deposit_id)?;
+
+        Ok(response
+            .add_attribute("action", "withdraw")
+            .add_attribute("sender", sender.to_string()))
+    }
+
+    /// Removes deposits of the owner, returning response refunding them together with their bonds
+    pub fn refund_deposits(
+        storage: &mut dyn Storage,
+        owner: &Addr,
+        deposit_id: Option<ID>,
     ) -> Result<Response, ContractError> {
         let keys_to_remove = if let Some(id) = deposit_id {
             // If ID is provided, remove only the entry with the provided address and ID
-            let deposit = DEPOSITS.load(deps.storage, (&sender, id))?.deposit;
-            vec![((&sender, id), deposit)]
+            let deposit = DEPOSITS.load(storage, (owner, id))?;
+            vec![((owner, id), deposit)]
         } else {
             // If ID is not provided, remove all entries with the provided address prefix
             DEPOSITS
-                .prefix(&sender)
-                .range(deps.storage, None, None, Order::Ascending)
+                .prefix(owner)
+                .range(storage, None, None, Order::Ascending)
                 .map(|item| {
                     let (id, deposit) = item?;
-                    Ok(((&sender, id), deposit.deposit))
+                    Ok(((owner, id), deposit))
                 })
-                .collect::<StdResult<Vec<((&Addr, ID), Asset)>>>()?
+                .collect::<StdResult<Vec<((&Addr, ID), Deposit)>>>()?
         };
 
-        let mut msgs = vec![];
-        for (key, deposit) in keys_to_remove {
-            DEPOSITS.remove(deps.storage, key);
-            let msg: CosmosMsg = match deposit.denom {
-                AssetType::Native(denom) => BankMsg::Send {
-                    to_address: sender.to_string(),
-                    amount: coins(deposit.amount.u128(), denom),
-                }
-                .into(),
-                AssetType::Cw20(denom) => WasmMsg::Execute {
-                    contract_addr: denom.to_string(),
-                    msg: to_binary(&Cw20ExecuteMsg::Transfer {
-                        recipient: sender.to_string(),
-                        amount: deposit.amount,
-                    })?,
-                    funds: vec![],
-                }
-                .into(),
-            };
-            msgs.push(msg);
+        let mut response = Response::new();
+        for ((_, id), deposit) in keys_to_remove {
+            remove_deposit(storage, owner, Some(id))?;
+            response = response
+                .add_message(transfer_message(owner, &deposit.deposit)?)
+                .add_messages(close_auction(storage, id)?);
+            if let Some(bond) = deposit.bond {
+                response = response.add_message(transfer_message(owner, &bond)?);
+            }
+            response = response.add_event(Event::from(OtcEvent::Withdraw {
+                id,
+                maker: owner.clone(),
+                deposit: deposit.deposit,
+            }));
         }
 
-        Ok(Response::new()
-            .add_messages(msgs)
-            .add_attribute("action", "withdraw")
-            .add_attribute("sender", sender.to_string()))
-    }
+        // Withdrawing everything returns also bonds prepaid and not used yet
+        if deposit_id.is_none() {
+            response = response.add_messages(refund_bond_credit(storage, owner)?);
+        }
 
-    pub fn accept_exchange(
-        deps: DepsMut,
-        sender: Addr,
-        deposit_id: ID,
-        offer_funds: Asset,
-    ) -> Result<Response, ContractError> {
-        let DepositByIdResponse {
-            sender: deposit_sender,
-            deposit,
-        } = query::deposit_by_id(deps.as_ref(), deposit_id)?;
+        Ok(response)
+    }
 
-        let exchange_messages = match deposit.offer.exchange.denom.clone() {
-            AssetType::Native(denom) => {
-                if offer_funds.denom.to_string() == denom {
-                    if offer_funds.amount == deposit.offer.exchange.amount {
-                        // Create two messages
-                        // First sends newly received native funds to the depositor,
-                        // second sends original deposit to user that accepted the exchange
-                        create_exchange_messages(
-                            &deposit_sender,
-                            &offer_funds,
-                            &sender,
-                            &deposit.deposit,
-                        )?
-                    } else {
-                        // User sent incorrect amount of native tokens to accept the exchange
-                        return Err(ContractError::ExchangeIncorrectAmount {
-                            expected_amount: deposit.offer.exchange.amount,
-                            provided_amount: offer_funds.amount,
-                        });
-                    }
-                } else {
-                    // User sent incorrect native token to the exchange
-                    return Err(ContractError::ExchangeIncorrectDenom {
-                        expected: denom,
-                        received: offer_funds.denom.to_string(),
-                    });
-                }
-            }
-            AssetType::Cw20(denom) => {
-                if offer_funds.denom.to_string() == denom {
-                    if offer_funds.amount == deposit.offer.exchange.amount {
-                        // Create two messages
-                        // First sends newly received cw20 funds to the depositor,
-                        // second sends original deposit to user that accepted the exchange
-                        create_exchange_messages(
-                            &deposit_sender,
-                            &offer_funds,
-                            &sender,
-                            &deposit.deposit,
-                        )?
-                    } else {
-                        // User sent incorrect amount of cw20 tokens to accept the exchange
-                        return Err(ContractError::ExchangeIncorrectAmount {
-                            expected_amount: deposit.offer.exchange.amount,
-                            provided_amount: offer_funds.amount,
-                        });
-                    }
-                } else {
-                    // User sent incorrect cw20 token to the exchange
-                    return Err(ContractError::ExchangeIncorrectDenom {
-                        expected: denom,
-                        received: offer_funds.denom.to_string(),
-                    });
-                }
-            }
+    /// Removes bond credit of the owner, returning its refund in the denom it was paid in
+    pub fn refund_bond_credit(
+        storage: &mut dyn Storage,
+        owner: &Addr,
+    ) -> StdResult<Option<CosmosMsg>> {
+        let credit = match BOND_CREDITS.may_load(storage, owner)? {
+            Some(credit) => credit,
+            None => return Ok(None),
         };
+        BOND_CREDITS.remove(storage, owner);
+        if credit.amount.is_zero() {
+            return Ok(None);
+        }
+        transfer_message(
+            owner,
+            &Asset::new_native(credit.amount.u128(), &credit.denom),
+        )
+        .map(Some)
+    }
 
-        Ok(Response::new()
-            .add_messages(exchange_messages)
-            .add_attribute("exchange", "completed")
-            .add_attribute("deposit-sender", deposit_sender.to_string())
-            .add_attribute("original-deposit", deposit.deposit.to_string())
-            .add_attribute("expected", deposit.offer.exchange.to_string())
-            .add_attribute("accepted-by", sender.to_string()))
+    /// Outcome of accepting an offer, computed before any state changes
+    pub struct FillQuote {
+        pub maker: Addr,
+        pub deposit: Deposit,
+        /// Exchange asset required by the offer, sent to the maker
+        pub exchange: Asset,
+        pub fee_amount: Uint128,
+        /// Deposit after the service fee, sent to the taker
+        pub taker_asset: Asset,
+        /// Funds above the expected amount, refunded to the taker
+        pub surplus: Uint128,
     }
 
-    pub fn create_exchange_messages(
-        first_party: &Addr,
-        first_asset: &Asset,
-        second_party: &Addr,
-        second_asset: &Asset,
-    ) -> StdResult<Vec<CosmosMsg>> {
-        let first_message: CosmosMsg = match first_asset.denom.clone() {
-            AssetType::Native(denom) => BankMsg::Send {
-                to_address: first_party.to_string(),
-                amount: coins(first_asset.amount.u128(), denom),
+    /// Service fee is paid by the user accepting the exchange, deducted from the deposit
+    /// they receive; the rate depends on their volume tier and staked balance
+    fn taker_fee(
+        deps: Deps,
+        taker: &Addr,
+        deposit: &Asset,
+    ) -> Result<(Uint128, Asset), ContractError> {
+        let (_, _, service_fee) = fee_tier(deps.storage, taker)?;
+        let discount = staking_discount(deps, taker)?;
+        // Rate is combined first, so the amount is rounded down only once
+        let fee_rate = service_fee * (Decimal::one() - discount);
+        let fee_amount = deposit.amount * fee_rate;
+        let taker_asset = Asset {
+            denom: deposit.denom.clone(),
+            amount: deposit.amount - fee_amount,
+        };
+        Ok((fee_amount, taker_asset))
+    }
+
+    /// Validates acceptance of the offer by the taker with given funds
+    pub fn quote_fill(
+        deps: Deps,
+        env: &Env,
+        taker: &Addr,
+        deposit_id: ID,
+        offer_funds: &Asset,
+    ) -> Result<FillQuote, ContractError> {
+        ensure_not_paused(deps, |pause| pause.acceptances, "Acceptances")?;
+
+        let DepositByIdResponse {
+            sender: maker,
+            deposit,
+            ..
+        } = query::deposit_by_id(deps, deposit_id)?;
+        if ENGLISH_AUCTIONS.has(deps.storage, deposit_id)
+            || SEALED_BID_AUCTIONS.has(deps.storage, deposit_id)
+        {
+            return Err(ContractError::AuctionInProgress { id: deposit_id });
+        }
+        if matches!(deposit.offer.expires, Some(expires) if expires <= env.block.time) {
+            return Err(ContractError::OfferExpired { id: deposit_id });
+        }
+        if let Some(from) = &deposit.offer.from {
+            if from != taker {
+                return Err(ContractError::CounterpartyMismatch {
+                    id: deposit_id,
+                    from: from.to_string(),
+                });
             }
-            .into(),
-            AssetType::Cw20(denom) => WasmMsg::Execute {
-                contract_addr: denom,
-                msg: to_binary(&Cw20ExecuteMsg::Transfer {
-                    recipient: first_party.to_string(),
-                    amount: first_asset.amount,
-                })?,
-                funds: vec![],
+        }
+        ensure_permitted(
+            deps,
+            &[&deposit.deposit.denom, &deposit.offer.exchange.denom],
+        )?;
+
+        let (fee_amount, taker_asset) = taker_fee(deps, taker, &deposit.deposit)?;
+
+        let exchange = Asset {
+            denom: deposit.offer.exchange.denom.clone(),
+            amount: deposit
+                .offer
+                .required_amount(deposit.deposit.amount, env.block.time)?,
+        };
+        if offer_funds.denom != exchange.denom {
+            // User sent incorrect token to the exchange
+            return Err(ContractError::ExchangeIncorrectDenom {
+                expected: exchange.denom.to_string(),
+                received: offer_funds.denom.to_string(),
+            });
+        }
+        if offer_funds.amount < exchange.amount {
+            // User sent not enough tokens to accept the exchange
+            return Err(ContractError::ExchangeIncorrectAmount {
+                expected_amount: exchange.amount,
+                provided_amount: offer_funds.amount,
+            });
+        }
+
+        Ok(FillQuote {
+            maker,
+            fee_amount,
+            taker_asset,
+            surplus: offer_funds.amount - exchange.amount,
+            exchange,
+            deposit,
+        })
+    }
+
+    pub fn accept_exchange(
+        deps: DepsMut,
+        env: Env,
+        sender: Addr,
+        deposit_id: ID,
+        offer_funds: Asset,
+        referrer: Option<String>,
+    ) -> Result<Response, ContractError> {
+        let quote = quote_fill(deps.as_ref(), &env, &sender, deposit_id, &offer_funds)?;
+        let referrer = validate_referrer(deps.as_ref(), &sender, referrer)?;
+        execute_fill(deps, env, deposit_id, sender, quote, referrer)
+    }
+
+    /// Pays both parties of the fill, distributes the fee and records the trade
+    fn execute_fill(
+        deps: DepsMut,
+        env: Env,
+        deposit_id: ID,
+        sender: Addr,
+        quote: FillQuote,
+        referrer: Option<Addr>,
+    ) -> Result<Response, ContractError> {
+        let FillQuote {
+            maker: deposit_sender,
+            deposit,
+            exchange,
+            fee_amount,
+            taker_asset,
+            surplus,
+        } = quote;
+
+        // Create two messages
+        // First sends expected amount of newly received funds to the depositor,
+        // second sends original deposit to user that accepted the exchange
+        let mut exchange_messages =
+            create_exchange_messages(&deposit_sender, &exchange, &sender, &taker_asset)?;
+
+        if let Some(bond) = &deposit.bond {
+            exchange_messages.push(transfer_message(&deposit_sender, bond)?);
+        }
+
+        // Anything sent above expected amount is refunded to the user that accepted the exchange
+        if !surplus.is_zero() {
+            exchange_messages.push(transfer_message(
+                &sender,
+                &Asset {
+                    denom: exchange.denom.clone(),
+                    amount: surplus,
+                },
+            )?);
+        }
+
+        remove_deposit(deps.storage, &deposit_sender, Some(deposit_id))?;
+
+        // Referrers of both parties are credited with their share of the fee,
+        // the rest accrues in the contract until claimed by the fee recipients
+        let fee_config = FEE_CONFIG.load(deps.storage)?;
+        let referral_reward = Asset {
+            denom: deposit.deposit.denom.clone(),
+            amount: fee_amount * fee_config.referral_share,
+        };
+        let mut protocol_fee = fee_amount;
+        if !referral_reward.amount.is_zero() {
+            for referrer in [&deposit.referrer, &referrer].into_iter().flatten() {
+                add_referral_reward(deps.storage, referrer, &referral_reward)?;
+                protocol_fee -= referral_reward.amount;
+            }
+        }
+
+        if !protocol_fee.is_zero() {
+            add_accrued_fee(
+                deps.storage,
+                &Asset {
+                    denom: deposit.deposit.denom.clone(),
+                    amount: protocol_fee,
+                },
+            )?;
+        }
+
+        if let Some(volume_config) = VOLUME_CONFIG.may_load(deps.storage)? {
+            let volume = if deposit.deposit.denom == volume_config.reference_denom {
+                Some(deposit.deposit.amount)
+            } else if exchange.denom == volume_config.reference_denom {
+                Some(exchange.amount)
+            } else {
+                None
+            };
+            if let Some(volume) = volume {
+                add_volume(deps.storage, &deposit_sender, volume)?;
+                add_volume(deps.storage, &sender, volume)?;
+            }
+        }
+
+        let fill = FillResponse {
+            id: deposit_id,
+            maker: deposit_sender,
+            taker: sender,
+            paid: exchange,
+            received: taker_asset,
+            fee: Asset {
+                denom: deposit.deposit.denom.clone(),
+                amount: fee_amount,
+            },
+        };
+        let record = Fill {
+            deposit_id,
+            maker: fill.maker.clone(),
+            taker: fill.taker.clone(),
+            paid: fill.paid.clone(),
+            received: fill.received.clone(),
+            fee: fill.fee.clone(),
+            time: env.block.time,
+            height: env.block.height,
+        };
+        fills().save(deps.storage, deposit_id, &record)?;
+        add_pair_fill(deps.storage, &record)?;
+        Ok(Response::new()
+            .add_messages(exchange_messages)
+            .add_attribute("exchange", "completed")
+            .add_attribute("id", deposit_id.to_string())
+            .add_attribute("deposit-sender", fill.maker.to_string())
+            .add_attribute("original-deposit", deposit.deposit.to_string())
+            .add_attribute("expected", fill.paid.to_string())
+            .add_attribute("accepted-by", fill.taker.to_string())
+            .add_attribute("fee", fee_amount.to_string())
+            .set_data(to_binary(&fill)?)
+            .add_event(Event::from(OtcEvent::Fill(fill))))
+    }
+
+    pub fn open_auction(
+        deps: DepsMut,
+        env: Env,
+        sender: Addr,
+        deposit_id: ID,
+        min_increment: Uint128,
+        end: Timestamp,
+        extension: u64,
+    ) -> Result<Response, ContractError> {
+        ensure_not_paused(deps.as_ref(), |pause| pause.deposits, "Deposits")?;
+
+        let deposit = DEPOSITS.load(deps.storage, (&sender, deposit_id))?;
+        if ENGLISH_AUCTIONS.has(deps.storage, deposit_id)
+            || SEALED_BID_AUCTIONS.has(deps.storage, deposit_id)
+        {
+            return Err(ContractError::AuctionInProgress { id: deposit_id });
+        }
+        // Expiration would let the deposit be swept from under a running auction
+        if deposit.offer.expires.is_some()
+            || end <= env.block.time
+            || min_increment.is_zero()
+            || extension > MAX_AUCTION_EXTENSION
+        {
+            return Err(ContractError::InvalidAuction {});
+        }
+        ensure_permitted(
+            deps.as_ref(),
+            &[&deposit.deposit.denom, &deposit.offer.exchange.denom],
+        )?;
+
+        let reserve = Asset {
+            denom: deposit.offer.exchange.denom.clone(),
+            amount: deposit
+                .offer
+                .required_amount(deposit.deposit.amount, env.block.time)?,
+        };
+        ENGLISH_AUCTIONS.save(
+            deps.storage,
+            deposit_id,
+            &EnglishAuction {
+                reserve: reserve.clone(),
+                min_increment,
+                end,
+                extension,
+                best_bid: None,
+            },
+        )?;
+
+        Ok(Response::new()
+            .add_attribute("action", "open_auction")
+            .add_attribute("id", deposit_id.to_string())
+            .add_attribute("reserve", reserve.to_string())
+            .add_attribute("end", end.to_string()))
+    }
+
+    pub fn bid(
+        deps: DepsMut,
+        env: Env,
+        sender: Addr,
+        deposit_id: ID,
+        funds: Asset,
+    ) -> Result<Response, ContractError> {
+        ensure_not_paused(deps.as_ref(), |pause| pause.acceptances, "Acceptances")?;
+
+        let DepositByIdResponse {
+            sender: maker,
+            deposit,
+            ..
+        } = query::deposit_by_id(deps.as_ref(), deposit_id)?;
+        let mut auction = ENGLISH_AUCTIONS.load(deps.storage, deposit_id)?;
+        if maker == sender {
+            return Err(ContractError::Unauthorized {});
+        }
+        if let Some(from) = deposit.offer.from {
+            if from != sender {
+                return Err(ContractError::CounterpartyMismatch {
+                    id: deposit_id,
+                    from: from.to_string(),
+                });
+            }
+        }
+        if env.block.time >= auction.end {
+            return Err(ContractError::AuctionEnded { id: deposit_id });
+        }
+        ensure_permitted(
+            deps.as_ref(),
+            &[&deposit.deposit.denom, &auction.reserve.denom],
+        )?;
+        if funds.denom != auction.reserve.denom {
+            return Err(ContractError::ExchangeIncorrectDenom {
+                expected: auction.reserve.denom.to_string(),
+                received: funds.denom.to_string(),
+            });
+        }
+        let min = match &auction.best_bid {
+            Some(best) => best.amount + auction.min_increment,
+            None => auction.reserve.amount,
+        };
+        if funds.amount < min {
+            return Err(ContractError::BidTooLow {
+                min: Asset {
+                    denom: funds.denom,
+                    amount: min,
+                }
+                .to_string(),
+            });
+        }
+
+        let mut response = Response::new();
+        if let Some(outbid) = auction.best_bid.take() {
+            response = response.add_message(transfer_message(
+                &outbid.bidder,
+                &Asset {
+                    denom: funds.denom.clone(),
+                    amount: outbid.amount,
+                },
+            )?);
+        }
+        // Late bids extend the auction, giving others a chance to respond
+        let extended = env.block.time.plus_seconds(auction.extension);
+        if extended > auction.end {
+            auction.end = extended;
+        }
+        auction.best_bid = Some(Bid {
+            bidder: sender.clone(),
+            amount: funds.amount,
+        });
+        ENGLISH_AUCTIONS.save(deps.storage, deposit_id, &auction)?;
+
+        Ok(response
+            .add_attribute("action", "bid")
+            .add_attribute("id", deposit_id.to_string())
+            .add_attribute("bidder", sender.to_string())
+            .add_attribute("bid", funds.to_string())
+            .add_attribute("end", auction.end.to_string()))
+    }
+
+    pub fn settle_auction(
+        deps: DepsMut,
+        env: Env,
+        deposit_id: ID,
+    ) -> Result<Response, ContractError> {
+        if SEALED_BID_AUCTIONS.has(deps.storage, deposit_id) {
+            return settle_sealed_bid_auction(deps, env, deposit_id);
+        }
+        let auction = ENGLISH_AUCTIONS.load(deps.storage, deposit_id)?;
+        if env.block.time < auction.end {
+            return Err(ContractError::AuctionNotEnded { id: deposit_id });
+        }
+
+        let response = match auction.best_bid {
+            Some(bid) => {
+                let DepositByIdResponse {
+                    sender: maker,
+                    deposit,
+                    ..
+                } = query::deposit_by_id(deps.as_ref(), deposit_id)?;
+                if !can_settle(deps.as_ref(), &deposit, &auction.reserve.denom)? {
+                    let refunds = close_auction(deps.storage, deposit_id)?;
+                    return Ok(Response::new()
+                        .add_messages(refunds)
+                        .add_attribute("action", "settle_auction")
+                        .add_attribute("id", deposit_id.to_string())
+                        .add_attribute("settled", "false"));
+                }
+                ENGLISH_AUCTIONS.remove(deps.storage, deposit_id);
+                let (fee_amount, taker_asset) =
+                    taker_fee(deps.as_ref(), &bid.bidder, &deposit.deposit)?;
+                let quote = FillQuote {
+                    maker,
+                    deposit,
+                    exchange: Asset {
+                        denom: auction.reserve.denom,
+                        amount: bid.amount,
+                    },
+                    fee_amount,
+                    taker_asset,
+                    surplus: Uint128::zero(),
+                };
+                execute_fill(deps, env, deposit_id, bid.bidder, quote, None)?
+            }
+            None => {
+                ENGLISH_AUCTIONS.remove(deps.storage, deposit_id);
+                Response::new()
             }
-            .into(),
         };
-        let second_message: CosmosMsg = match second_asset.denom.clone() {
+
+        Ok(response
+            .add_attribute("action", "settle_auction")
+            .add_attribute("id", deposit_id.to_string()))
+    }
+
+    /// Whether the winning bid of an auction may fill its offer; while acceptances are paused
+    /// or either asset is blocked, the auction is closed with bids refunded instead, so that
+    /// neither the bids nor the deposit get stuck
+    fn can_settle(deps: Deps, deposit: &Deposit, bid_denom: &AssetType) -> StdResult<bool> {
+        Ok(!PAUSE_STATE.load(deps.storage)?.acceptances
+            && is_asset_permitted(deps.storage, &deposit.deposit.denom)?
+            && is_asset_permitted(deps.storage, bid_denom)?)
+    }
+
+    pub fn open_sealed_bid_auction(
+        deps: DepsMut,
+        env: Env,
+        sender: Addr,
+        deposit_id: ID,
+        commit_end: Timestamp,
+        reveal_end: Timestamp,
+        penalty: Decimal,
+    ) -> Result<Response, ContractError> {
+        ensure_not_paused(deps.as_ref(), |pause| pause.deposits, "Deposits")?;
+
+        let deposit = DEPOSITS.load(deps.storage, (&sender, deposit_id))?;
+        if ENGLISH_AUCTIONS.has(deps.storage, deposit_id)
+            || SEALED_BID_AUCTIONS.has(deps.storage, deposit_id)
+        {
+            return Err(ContractError::AuctionInProgress { id: deposit_id });
+        }
+        // Unclaimed bids of an earlier auction would be mistaken for bids in this one
+        if UNREVEALED_ESCROWS.has(deps.storage, deposit_id) {
+            return Err(ContractError::UnclaimedEscrows { id: deposit_id });
+        }
+        if deposit.offer.expires.is_some()
+            || commit_end <= env.block.time
+            || reveal_end <= commit_end
+        {
+            return Err(ContractError::InvalidAuction {});
+        }
+        if penalty < Decimal::percent(MIN_SEALED_BID_PENALTY_PERCENT) || penalty > Decimal::one() {
+            return Err(ContractError::InvalidPenalty {});
+        }
+        ensure_permitted(
+            deps.as_ref(),
+            &[&deposit.deposit.denom, &deposit.offer.exchange.denom],
+        )?;
+
+        let reserve = Asset {
+            denom: deposit.offer.exchange.denom.clone(),
+            amount: deposit
+                .offer
+                .required_amount(deposit.deposit.amount, env.block.time)?,
+        };
+        SEALED_BID_AUCTIONS.save(
+            deps.storage,
+            deposit_id,
+            &SealedBidAuction {
+                reserve: reserve.clone(),
+                commit_end,
+                reveal_end,
+                penalty,
+                best_bid: None,
+                unrevealed: 0,
+                unrevealed_penalties: Uint128::zero(),
+            },
+        )?;
+
+        Ok(Response::new()
+            .add_attribute("action", "open_sealed_bid_auction")
+            .add_attribute("id", deposit_id.to_string())
+            .add_attribute("reserve", reserve.to_string())
+            .add_attribute("commit_end", commit_end.to_string())
+            .add_attribute("reveal_end", reveal_end.to_string()))
+    }
+
+    pub fn commit_bid(
+        deps: DepsMut,
+        env: Env,
+        sender: Addr,
+        deposit_id: ID,
+        commitment: Binary,
+        funds: Asset,
+    ) -> Result<Response, ContractError> {
+        ensure_not_paused(deps.as_ref(), |pause| pause.acceptances, "Acceptances")?;
+
+        let DepositByIdResponse {
+            sender: maker,
+            deposit,
+            ..
+        } = query::deposit_by_id(deps.as_ref(), deposit_id)?;
+        let mut auction = SEALED_BID_AUCTIONS.load(deps.storage, deposit_id)?;
+        if maker == sender {
+            return Err(ContractError::Unauthorized {});
+        }
+        if let Some(from) = deposit.offer.from {
+            if from != sender {
+                return Err(ContractError::CounterpartyMismatch {
+                    id: deposit_id,
+                    from: from.to_string(),
+                });
+            }
+        }
+        if env.block.time >= auction.commit_end {
+            return Err(ContractError::AuctionEnded { id: deposit_id });
+        }
+        ensure_permitted(
+            deps.as_ref(),
+            &[&deposit.deposit.denom, &auction.reserve.denom],
+        )?;
+        if funds.denom != auction.reserve.denom {
+            return Err(ContractError::ExchangeIncorrectDenom {
+                expected: auction.reserve.denom.to_string(),
+                received: funds.denom.to_string(),
+            });
+        }
+        // Escrow below the reserve can't cover any valid bid
+        if funds.amount < auction.reserve.amount {
+            return Err(ContractError::BidTooLow {
+                min: auction.reserve.to_string(),
+            });
+        }
+        if SEALED_BIDS.has(deps.storage, (deposit_id, &sender)) {
+            return Err(ContractError::BidAlreadyCommitted {});
+        }
+
+        let penalty = funds.amount * auction.penalty;
+        SEALED_BIDS.save(
+            deps.storage,
+            (deposit_id, &sender),
+            &SealedBid {
+                commitment,
+                escrow: funds.amount,
+                penalty,
+            },
+        )?;
+        auction.unrevealed += 1;
+        auction.unrevealed_penalties += penalty;
+        SEALED_BID_AUCTIONS.save(deps.storage, deposit_id, &auction)?;
+
+        Ok(Response::new()
+            .add_attribute("action", "commit_bid")
+            .add_attribute("id", deposit_id.to_string())
+            .add_attribute("bidder", sender.to_string())
+            .add_attribute("escrow", funds.to_string()))
+    }
+
+    pub fn reveal_bid(
+        deps: DepsMut,
+        env: Env,
+        sender: Addr,
+        deposit_id: ID,
+        amount: Uint128,
+        salt: String,
+    ) -> Result<Response, ContractError> {
+        let mut auction = SEALED_BID_AUCTIONS.load(deps.storage, deposit_id)?;
+        if env.block.time < auction.commit_end {
+            return Err(ContractError::AuctionNotEnded { id: deposit_id });
+        }
+        if env.block.time >= auction.reveal_end {
+            return Err(ContractError::AuctionEnded { id: deposit_id });
+        }
+        let bid = SEALED_BIDS.load(deps.storage, (deposit_id, &sender))?;
+        if sealed_bid_commitment(deposit_id, &sender, amount, &salt) != bid.commitment {
+            return Err(ContractError::CommitmentMismatch {});
+        }
+        SEALED_BIDS.remove(deps.storage, (deposit_id, &sender));
+        auction.unrevealed -= 1;
+        auction.unrevealed_penalties -= bid.penalty;
+
+        let valid = amount >= auction.reserve.amount && amount <= bid.escrow;
+        let leading = valid
+            && match &auction.best_bid {
+                Some(best) => amount > best.amount,
+                None => true,
+            };
+        let mut response = Response::new();
+        // Bids below the reserve or above their escrow are invalid and forfeit the penalty,
+        // like unrevealed ones, so that revealing a junk bid doesn't dodge it
+        let penalty = if valid { Uint128::zero() } else { bid.penalty };
+        if !penalty.is_zero() {
+            let maker = query::deposit_by_id(deps.as_ref(), deposit_id)?.sender;
+            response = response.add_message(transfer_message(
+                &maker,
+                &Asset {
+                    denom: auction.reserve.denom.clone(),
+                    amount: penalty,
+                },
+            )?);
+        }
+        let refund = if leading {
+            auction.best_bid.replace(RevealedBid {
+                bidder: sender.clone(),
+                amount,
+                escrow: bid.escrow,
+            })
+        } else {
+            Some(RevealedBid {
+                bidder: sender.clone(),
+                amount,
+                escrow: bid.escrow - penalty,
+            })
+        };
+        SEALED_BID_AUCTIONS.save(deps.storage, deposit_id, &auction)?;
+
+        if let Some(refund) = refund.filter(|refund| !refund.escrow.is_zero()) {
+            response = response.add_message(transfer_message(
+                &refund.bidder,
+                &Asset {
+                    denom: auction.reserve.denom,
+                    amount: refund.escrow,
+                },
+            )?);
+        }
+        Ok(response
+            .add_attribute("action", "reveal_bid")
+            .add_attribute("id", deposit_id.to_string())
+            .add_attribute("bidder", sender.to_string())
+            .add_attribute("bid", amount.to_string())
+            .add_attribute("leading", leading.to_string())
+            .add_attribute("penalty", penalty.to_string()))
+    }
+
+    fn settle_sealed_bid_auction(
+        deps: DepsMut,
+        env: Env,
+        deposit_id: ID,
+    ) -> Result<Response, ContractError> {
+        let auction = SEALED_BID_AUCTIONS.load(deps.storage, deposit_id)?;
+        if env.block.time < auction.reveal_end {
+            return Err(ContractError::AuctionNotEnded { id: deposit_id });
+        }
+        let DepositByIdResponse {
+            sender: maker,
+            deposit,
+            ..
+        } = query::deposit_by_id(deps.as_ref(), deposit_id)?;
+        if auction.best_bid.is_some()
+            && !can_settle(deps.as_ref(), &deposit, &auction.reserve.denom)?
+        {
+            let refunds = close_auction(deps.storage, deposit_id)?;
+            return Ok(Response::new()
+                .add_messages(refunds)
+                .add_attribute("action", "settle_auction")
+                .add_attribute("id", deposit_id.to_string())
+                .add_attribute("settled", "false"));
+        }
+        SEALED_BID_AUCTIONS.remove(deps.storage, deposit_id);
+
+        // Bids left unrevealed forfeit the penalty to the maker, bidders claim the rest of
+        // their escrow themselves so that settling costs the same however many bids there are
+        leave_unrevealed_escrows(deps.storage, deposit_id, &auction, true)?;
+        let penalties = auction.unrevealed_penalties;
+        let penalty_msg = if penalties.is_zero() {
+            None
+        } else {
+            Some(transfer_message(
+                &maker,
+                &Asset {
+                    denom: auction.reserve.denom.clone(),
+                    amount: penalties,
+                },
+            )?)
+        };
+
+        let response = match auction.best_bid {
+            Some(bid) => {
+                let (fee_amount, taker_asset) =
+                    taker_fee(deps.as_ref(), &bid.bidder, &deposit.deposit)?;
+                let quote = FillQuote {
+                    maker,
+                    deposit,
+                    exchange: Asset {
+                        denom: auction.reserve.denom,
+                        amount: bid.amount,
+                    },
+                    fee_amount,
+                    taker_asset,
+                    // Escrow above the revealed bid goes back to the winner
+                    surplus: bid.escrow - bid.amount,
+                };
+                execute_fill(deps, env, deposit_id, bid.bidder, quote, None)?
+            }
+            None => Response::new(),
+        };
+
+        Ok(response
+            .add_messages(penalty_msg)
+            .add_attribute("action", "settle_auction")
+            .add_attribute("id", deposit_id.to_string())
+            .add_attribute("penalties", penalties.to_string()))
+    }
+
+    /// Leaves escrows of bids not revealed before the auction ended to be claimed by bidders
+    fn leave_unrevealed_escrows(
+        storage: &mut dyn Storage,
+        deposit_id: ID,
+        auction: &SealedBidAuction,
+        penalized: bool,
+    ) -> StdResult<()> {
+        if auction.unrevealed > 0 {
+            UNREVEALED_ESCROWS.save(
+                storage,
+                deposit_id,
+                &UnrevealedEscrows {
+                    denom: auction.reserve.denom.clone(),
+                    penalized,
+                    unclaimed: auction.unrevealed,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn claim_bid_escrow(
+        deps: DepsMut,
+        sender: Addr,
+        deposit_id: ID,
+    ) -> Result<Response, ContractError> {
+        ensure_not_paused(deps.as_ref(), |pause| pause.withdrawals, "Withdrawals")?;
+
+        // Bids are still revealed or forfeit their penalty on settlement
+        if SEALED_BID_AUCTIONS.has(deps.storage, deposit_id) {
+            return Err(ContractError::AuctionNotEnded { id: deposit_id });
+        }
+        let bid = SEALED_BIDS.load(deps.storage, (deposit_id, &sender))?;
+        let mut escrows = UNREVEALED_ESCROWS.load(deps.storage, deposit_id)?;
+        SEALED_BIDS.remove(deps.storage, (deposit_id, &sender));
+        escrows.unclaimed -= 1;
+        if escrows.unclaimed == 0 {
+            UNREVEALED_ESCROWS.remove(deps.storage, deposit_id);
+        } else {
+            UNREVEALED_ESCROWS.save(deps.storage, deposit_id, &escrows)?;
+        }
+
+        let refund = Asset {
+            amount: if escrows.penalized {
+                bid.escrow - bid.penalty
+            } else {
+                bid.escrow
+            },
+            denom: escrows.denom,
+        };
+        let mut response = Response::new();
+        if !refund.amount.is_zero() {
+            response = response.add_message(transfer_message(&sender, &refund)?);
+        }
+        Ok(response
+            .add_attribute("action", "claim_bid_escrow")
+            .add_attribute("id", deposit_id.to_string())
+            .add_attribute("bidder", sender.to_string())
+            .add_attribute("refund", refund.to_string()))
+    }
+
+    /// Removes auction of the deposit, if any, returning refund of its best bid
+    /// Escrows of sealed bids not revealed yet are left for their bidders to claim in full
+    fn close_auction(
+        storage: &mut dyn Storage,
+        deposit_id: ID,
+    ) -> Result<Vec<CosmosMsg>, ContractError> {
+        let mut refunds = vec![];
+        if let Some(auction) = ENGLISH_AUCTIONS.may_load(storage, deposit_id)? {
+            ENGLISH_AUCTIONS.remove(storage, deposit_id);
+            if let Some(bid) = auction.best_bid {
+                refunds.push(transfer_message(
+                    &bid.bidder,
+                    &Asset {
+                        denom: auction.reserve.denom,
+                        amount: bid.amount,
+                    },
+                )?);
+            }
+        }
+        if let Some(auction) = SEALED_BID_AUCTIONS.may_load(storage, deposit_id)? {
+            SEALED_BID_AUCTIONS.remove(storage, deposit_id);
+            // Bids not revealed yet are claimed back whole by their bidders
+            leave_unrevealed_escrows(storage, deposit_id, &auction, false)?;
+            if let Some(bid) = auction.best_bid {
+                refunds.push(transfer_message(
+                    &bid.bidder,
+                    &Asset {
+                        denom: auction.reserve.denom,
+                        amount: bid.escrow,
+                    },
+                )?);
+            }
+        }
+        Ok(refunds)
+    }
+
+    pub fn claim_referral_rewards(deps: DepsMut, sender: Addr) -> Result<Response, ContractError> {
+        let rewards = get_referral_rewards(deps.storage, &sender)?;
+        if rewards.is_empty() {
+            return Err(ContractError::NoReferralRewards {});
+        }
+
+        let mut msgs = vec![];
+        for reward in rewards {
+            REFERRAL_REWARDS.remove(deps.storage, (&sender, &reward.denom.key()));
+            msgs.push(transfer_message(&sender, &reward)?);
+        }
+
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_attribute("action", "claim_referral_rewards")
+            .add_attribute("sender", sender.to_string()))
+    }
+
+    pub fn claim_fees(
+        deps: DepsMut,
+        sender: Addr,
+        assets: Option<Vec<AssetType>>,
+    ) -> Result<Response, ContractError> {
+        let fee_config = FEE_CONFIG.load(deps.storage)?;
+        if !fee_config
+            .fee_recipients
+            .iter()
+            .any(|recipient| recipient.address == sender)
+        {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        let assets = match assets {
+            Some(assets) => assets,
+            None => get_accrued_fees(deps.storage)?
+                .into_iter()
+                .map(|fee| fee.denom)
+                .collect(),
+        };
+
+        // Each entry is removed as soon as it's read, so listing an asset twice claims it once
+        let mut msgs = vec![];
+        for asset in assets {
+            let fee = match ACCRUED_FEES.may_load(deps.storage, &asset.key())? {
+                Some(fee) => fee,
+                None => continue,
+            };
+            ACCRUED_FEES.remove(deps.storage, &asset.key());
+            msgs.extend(fee_distribution_messages(&fee_config.fee_recipients, &fee)?);
+        }
+        if msgs.is_empty() {
+            return Err(ContractError::NoFeesToClaim {});
+        }
+
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_attribute("action", "claim_fees")
+            .add_attribute("sender", sender.to_string()))
+    }
+
+    pub fn set_pause(
+        deps: DepsMut,
+        sender: Addr,
+        deposits: Option<bool>,
+        acceptances: Option<bool>,
+        withdrawals: Option<bool>,
+    ) -> Result<Response, ContractError> {
+        ensure_admin(deps.as_ref(), &sender)?;
+        update_pause(deps.storage, deposits, acceptances, withdrawals)
+    }
+
+    pub fn update_pause(
+        storage: &mut dyn Storage,
+        deposits: Option<bool>,
+        acceptances: Option<bool>,
+        withdrawals: Option<bool>,
+    ) -> Result<Response, ContractError> {
+        let pause = PAUSE_STATE.update(storage, |mut pause| -> StdResult<_> {
+            pause.deposits = deposits.unwrap_or(pause.deposits);
+            pause.acceptances = acceptances.unwrap_or(pause.acceptances);
+            pause.withdrawals = withdrawals.unwrap_or(pause.withdrawals);
+            Ok(pause)
+        })?;
+
+        Ok(Response::new()
+            .add_attribute("action", "set_pause")
+            .add_attribute("deposits", pause.deposits.to_string())
+            .add_attribute("acceptances", pause.acceptances.to_string())
+            .add_attribute("withdrawals", pause.withdrawals.to_string()))
+    }
+
+    pub fn update_asset_registry(
+        deps: DepsMut,
+        sender: Addr,
+        allow: Vec<AssetType>,
+        block: Vec<AssetType>,
+        remove: Vec<AssetType>,
+        allowlist_only: Option<bool>,
+    ) -> Result<Response, ContractError> {
+        ensure_admin(deps.as_ref(), &sender)?;
+
+        let listings = allow
+            .into_iter()
+            .map(|asset| (asset, AssetListing::Allowed))
+            .chain(
+                block
+                    .into_iter()
+                    .map(|asset| (asset, AssetListing::Blocked)),
+            );
+        for (asset, listing) in listings {
+            ASSET_REGISTRY.save(
+                deps.storage,
+                &asset.key(),
+                &AssetRegistryEntry { asset, listing },
+            )?;
+        }
+        for asset in remove {
+            ASSET_REGISTRY.remove(deps.storage, &asset.key());
+        }
+        if let Some(allowlist_only) = allowlist_only {
+            ALLOWLIST_ONLY.save(deps.storage, &allowlist_only)?;
+        }
+
+        Ok(Response::new().add_attribute("action", "update_asset_registry"))
+    }
+
+    pub fn set_balance_delta_accounting(
+        deps: DepsMut,
+        env: Env,
+        sender: Addr,
+        token: String,
+        enabled: bool,
+    ) -> Result<Response, ContractError> {
+        ensure_admin(deps.as_ref(), &sender)?;
+
+        let token = deps.api.addr_validate(&token)?;
+        if enabled {
+            let balance = query_cw20_balance(deps.as_ref(), &token, &env.contract.address)?;
+            CW20_RESERVES.save(deps.storage, &token, &balance)?;
+        } else {
+            CW20_RESERVES.remove(deps.storage, &token);
+        }
+
+        Ok(Response::new()
+            .add_attribute("action", "set_balance_delta_accounting")
+            .add_attribute("token", token.to_string())
+            .add_attribute("enabled", enabled.to_string()))
+    }
+
+    pub fn prepay_bond(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+        let bond = ANTI_SPAM_CONFIG
+            .load(deps.storage)?
+            .offer_bond
+            .ok_or(ContractError::NonPayable {})?;
+        let funds = one_native_asset(&info)?;
+        if funds.denom != AssetType::Native(bond.denom.clone()) {
+            return Err(ContractError::BondNotProvided {
+                bond: bond.to_string(),
+            });
+        }
+
+        // Credit prepaid in a former bond denom can't pay for bonds anymore, so it's returned
+        let mut response = Response::new();
+        let mut credit = match BOND_CREDITS.may_load(deps.storage, &info.sender)? {
+            Some(credit) if credit.denom == bond.denom => credit,
+            Some(_) => {
+                response = response.add_messages(refund_bond_credit(deps.storage, &info.sender)?);
+                coin(0, &bond.denom)
+            }
+            None => coin(0, &bond.denom),
+        };
+        credit.amount += funds.amount;
+        BOND_CREDITS.save(deps.storage, &info.sender, &credit)?;
+
+        Ok(response
+            .add_attribute("action", "prepay_bond")
+            .add_attribute("sender", info.sender.to_string())
+            .add_attribute("credit", credit.to_string()))
+    }
+
+    pub fn update_anti_spam_config(
+        deps: DepsMut,
+        sender: Addr,
+        max_offers_per_address: Option<u32>,
+        offer_bond: Option<Coin>,
+        keeper_bounty: Option<Decimal>,
+        min_deposits: Vec<Asset>,
+    ) -> Result<Response, ContractError> {
+        ensure_admin(deps.as_ref(), &sender)?;
+
+        let mut config = ANTI_SPAM_CONFIG.load(deps.storage)?;
+        if let Some(max) = max_offers_per_address {
+            config.max_offers_per_address = if max == 0 { None } else { Some(max) };
+        }
+        if let Some(bond) = offer_bond {
+            config.offer_bond = if bond.amount.is_zero() {
+                None
+            } else {
+                Some(bond)
+            };
+        }
+        if let Some(keeper_bounty) = keeper_bounty {
+            if keeper_bounty > Decimal::one() {
+                return Err(ContractError::InvalidKeeperBounty {});
+            }
+            config.keeper_bounty = keeper_bounty;
+        }
+        ANTI_SPAM_CONFIG.save(deps.storage, &config)?;
+        for min in min_deposits {
+            if min.amount.is_zero() {
+                MIN_DEPOSITS.remove(deps.storage, &min.denom.key());
+            } else {
+                MIN_DEPOSITS.save(deps.storage, &min.denom.key(), &min)?;
+            }
+        }
+
+        Ok(Response::new().add_attribute("action", "update_anti_spam_config"))
+    }
+
+    pub fn sweep_expired(
+        deps: DepsMut,
+        env: Env,
+        sender: Addr,
+        limit: Option<u32>,
+    ) -> Result<Response, ContractError> {
+        ensure_not_paused(deps.as_ref(), |pause| pause.withdrawals, "Withdrawals")?;
+
+        let (msgs, events) = remove_expired(deps.storage, env.block.time, Some(&sender), limit)?;
+
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_attribute("action", "sweep_expired")
+            .add_attribute("sender", sender.to_string())
+            .add_attribute("swept", events.len().to_string())
+            .add_events(events))
+    }
+
+    pub fn emergency_refund(
+        deps: DepsMut,
+        env: Env,
+        sender: Addr,
+        limit: Option<u32>,
+    ) -> Result<Response, ContractError> {
+        ensure_admin(deps.as_ref(), &sender)?;
+
+        let limit = limit.unwrap_or(DEFAULT_SWEEP_LIMIT).min(MAX_SWEEP_LIMIT) as usize;
+        // Refunded deposits are removed, so every page starts from the beginning
+        let deposits = DEPOSITS
+            .range(deps.storage, None, None, Order::Ascending)
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?;
+
+        let mut response = Response::new()
+            .add_attribute("action", "emergency_refund")
+            .add_attribute("sender", sender.to_string())
+            .add_attribute("refunded", deposits.len().to_string());
+        let remaining = limit - deposits.len();
+        for ((owner, id), deposit) in deposits {
+            remove_deposit(deps.storage, &owner, Some(id))?;
+            response = response
+                .add_message(transfer_message(&owner, &deposit.deposit)?)
+                .add_messages(close_auction(deps.storage, id)?);
+            let mut event = Event::new("emergency_refund")
+                .add_attribute("id", id.to_string())
+                .add_attribute("owner", owner.to_string())
+                .add_attribute("deposit", deposit.deposit.to_string());
+            if let Some(bond) = &deposit.bond {
+                response = response.add_message(transfer_message(&owner, bond)?);
+                event = event.add_attribute("bond", bond.to_string());
+            }
+            response = response
+                .add_event(event)
+                .add_event(Event::from(OtcEvent::Withdraw {
+                    id,
+                    maker: owner.clone(),
+                    deposit: deposit.deposit.clone(),
+                }));
+            CANCELLED_OFFERS.save(
+                deps.storage,
+                id,
+                &CancelledOffer {
+                    owner,
+                    deposit,
+                    time: env.block.time,
+                },
+            )?;
+        }
+
+        // Bond credits are returned once all deposits are, in what is left of the page
+        let owners = BOND_CREDITS
+            .keys(deps.storage, None, None, Order::Ascending)
+            .take(remaining)
+            .collect::<StdResult<Vec<Addr>>>()?;
+        for owner in owners {
+            let credit = BOND_CREDITS.load(deps.storage, &owner)?;
+            response = response
+                .add_messages(refund_bond_credit(deps.storage, &owner)?)
+                .add_event(
+                    Event::new("emergency_refund")
+                        .add_attribute("owner", owner.to_string())
+                        .add_attribute("bond_credit", credit.to_string()),
+                );
+        }
+
+        Ok(response)
+    }
+
+    /// Removes up to `limit` expired deposits, returning messages refunding them and
+    /// withdrawal events, one per deposit; without a keeper whole bonds go back to depositors
+    pub fn remove_expired(
+        storage: &mut dyn Storage,
+        now: Timestamp,
+        keeper: Option<&Addr>,
+        limit: Option<u32>,
+    ) -> Result<(Vec<CosmosMsg>, Vec<Event>), ContractError> {
+        let limit = limit.unwrap_or(DEFAULT_SWEEP_LIMIT).min(MAX_SWEEP_LIMIT) as usize;
+        let expired = get_expired_deposits(storage, now, limit)?;
+        let keeper_bounty = ANTI_SPAM_CONFIG.load(storage)?.keeper_bounty;
+
+        let mut msgs = vec![];
+        let mut events = vec![];
+        for (owner, id) in &expired {
+            let deposit = DEPOSITS.load(storage, (owner, *id))?;
+            remove_deposit(storage, owner, Some(*id))?;
+            msgs.push(transfer_message(owner, &deposit.deposit)?);
+            msgs.extend(close_auction(storage, *id)?);
+            events.push(Event::from(OtcEvent::Withdraw {
+                id: *id,
+                maker: owner.clone(),
+                deposit: deposit.deposit.clone(),
+            }));
+
+            // Keeper is paid out of the bond, the rest of it goes back to the depositor
+            if let Some(bond) = deposit.bond {
+                let mut refund = bond.amount;
+                if let Some(keeper) = keeper {
+                    let bounty = bond.amount * keeper_bounty;
+                    refund -= bounty;
+                    if !bounty.is_zero() {
+                        msgs.push(transfer_message(
+                            keeper,
+                            &Asset {
+                                denom: bond.denom.clone(),
+                                amount: bounty,
+                            },
+                        )?);
+                    }
+                }
+                if !refund.is_zero() {
+                    msgs.push(transfer_message(
+                        owner,
+                        &Asset {
+                            denom: bond.denom,
+                            amount: refund,
+                        },
+                    )?);
+                }
+            }
+        }
+
+        Ok((msgs, events))
+    }
+
+    /// Splits fee between recipients according to their weights
+    /// Rounding leftovers go to the first recipient
+    pub fn fee_distribution_messages(
+        recipients: &[FeeRecipient],
+        fee: &Asset,
+    ) -> StdResult<Vec<CosmosMsg>> {
+        let shares: Vec<Uint128> = recipients
+            .iter()
+            .map(|recipient| {
+                fee.amount
+                    .multiply_ratio(recipient.weight, FEE_WEIGHT_TOTAL)
+            })
+            .collect();
+        let leftover = fee.amount - shares.iter().sum::<Uint128>();
+
+        recipients
+            .iter()
+            .zip(shares)
+            .enumerate()
+            .map(|(i, (recipient, share))| {
+                let amount = if i == 0 { share + leftover } else { share };
+                (recipient, amount)
+            })
+            .filter(|(_, amount)| !amount.is_zero())
+            .map(|(recipient, amount)| {
+                transfer_message(
+                    &recipient.address,
+                    &Asset {
+                        denom: fee.denom.clone(),
+                        amount,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    pub fn transfer_message(recipient: &Addr, asset: &Asset) -> StdResult<CosmosMsg> {
+        Ok(match asset.denom.clone() {
             AssetType::Native(denom) => BankMsg::Send {
-                to_address: second_party.to_string(),
-                amount: coins(second_asset.amount.u128(), denom),
+                to_address: recipient.to_string(),
+                amount: coins(asset.amount.u128(), denom),
             }
             .into(),
             AssetType::Cw20(denom) => WasmMsg::Execute {
                 contract_addr: denom,
                 msg: to_binary(&Cw20ExecuteMsg::Transfer {
-                    recipient: second_party.to_string(),
-                    amount: second_asset.amount,
+                    recipient: recipient.to_string(),
+                    amount: asset.amount,
                 })?,
                 funds: vec![],
             }
             .into(),
-        };
-        Ok(vec![first_message, second_message])
+        })
+    }
+
+    pub fn create_exchange_messages(
+        first_party: &Addr,
+        first_asset: &Asset,
+        second_party: &Addr,
+        second_asset: &Asset,
+    ) -> StdResult<Vec<CosmosMsg>> {
+        Ok(vec![
+            transfer_message(first_party, first_asset)?,
+            transfer_message(second_party, second_asset)?,
+        ])
+    }
+}
+
+mod sudo {
+    use super::*;
+
+    pub fn update_fee_config(
+        deps: DepsMut,
+        fee_recipients: Option<Vec<FeeShare>>,
+        service_fee: Option<Decimal>,
+        referral_share: Option<Decimal>,
+        volume_config: Option<VolumeConfig>,
+    ) -> Result<Response, ContractError> {
+        let mut config = FEE_CONFIG.load(deps.storage)?;
+        if let Some(fee_recipients) = fee_recipients {
+            config.fee_recipients = validate_fee_recipients(deps.as_ref(), fee_recipients)?;
+        }
+        if let Some(service_fee) = service_fee {
+            if service_fee >= Decimal::one() {
+                return Err(ContractError::InvalidServiceFee {});
+            }
+            config.service_fee = service_fee;
+        }
+        if let Some(volume_config) = volume_config {
+            validate_volume_config(&volume_config, config.service_fee)?;
+            VOLUME_CONFIG.save(deps.storage, &volume_config)?;
+        } else if let Some(volume_config) = VOLUME_CONFIG.may_load(deps.storage)? {
+            validate_volume_config(&volume_config, config.service_fee)?;
+        }
+        if let Some(referral_share) = referral_share {
+            if referral_share > Decimal::percent(50) {
+                return Err(ContractError::InvalidReferralShare {});
+            }
+            config.referral_share = referral_share;
+        }
+        FEE_CONFIG.save(deps.storage, &config)?;
+
+        Ok(Response::new()
+            .add_attribute("action", "update_fee_config")
+            .add_attribute("service_fee", config.service_fee.to_string())
+            .add_attribute("referral_share", config.referral_share.to_string()))
+    }
+
+    pub fn force_refund(
+        deps: DepsMut,
+        address: String,
+        deposit_id: Option<ID>,
+    ) -> Result<Response, ContractError> {
+        let owner = deps.api.addr_validate(&address)?;
+        let response = execute::refund_deposits(deps.storage, &owner, deposit_id)?;
+
+        Ok(response
+            .add_attribute("action", "force_refund")
+            .add_attribute("owner", owner.to_string()))
+    }
+
+    pub fn expire_offers(
+        deps: DepsMut,
+        env: Env,
+        limit: Option<u32>,
+    ) -> Result<Response, ContractError> {
+        // Clock modules call this every block, so pause is respected without failing the call
+        if PAUSE_STATE.load(deps.storage)?.withdrawals {
+            return Ok(Response::new()
+                .add_attribute("action", "expire_offers")
+                .add_attribute("swept", "0"));
+        }
+
+        let (msgs, events) = execute::remove_expired(deps.storage, env.block.time, None, limit)?;
+
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_attribute("action", "expire_offers")
+            .add_attribute("swept", events.len().to_string())
+            .add_events(events))
     }
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match msg {
         QueryMsg::DepositsBySender { address } => {
             to_binary(&query::deposits_by_sender(deps, address)?)
         }
         QueryMsg::DepositById { id } => to_binary(&query::deposit_by_id(deps, id)?),
+        QueryMsg::FeeTier { address } => to_binary(&query::fee_tier(deps, address)?),
+        QueryMsg::ReferralRewards { address } => {
+            to_binary(&query::referral_rewards(deps, address)?)
+        }
+        QueryMsg::AccruedFees {} => to_binary(&query::accrued_fees(deps)?),
+        QueryMsg::PauseState {} => to_binary(&PAUSE_STATE.load(deps.storage)?),
+        QueryMsg::AssetRegistry { start_after, limit } => {
+            to_binary(&query::asset_registry(deps, start_after, limit)?)
+        }
+        QueryMsg::TokenMetadata { address } => to_binary(&query::token_metadata(deps, address)?),
+        QueryMsg::AntiSpamConfig {} => to_binary(&query::anti_spam_config(deps)?),
+        QueryMsg::Auction { deposit_id } => {
+            to_binary(&ENGLISH_AUCTIONS.load(deps.storage, deposit_id)?)
+        }
+        QueryMsg::UnrevealedEscrows { deposit_id } => {
+            to_binary(&UNREVEALED_ESCROWS.load(deps.storage, deposit_id)?)
+        }
+        QueryMsg::CancelledOffer { id } => to_binary(&CANCELLED_OFFERS.load(deps.storage, id)?),
+        QueryMsg::SealedBidAuction { deposit_id } => {
+            to_binary(&SEALED_BID_AUCTIONS.load(deps.storage, deposit_id)?)
+        }
+        QueryMsg::TradeHistory {
+            filter,
+            start_after,
+            limit,
+        } => to_binary(&query::trade_history(deps, filter, start_after, limit)?),
+        QueryMsg::PairStats { pair } => to_binary(&query::pair_stats(deps, pair)?),
+        QueryMsg::Vwap { pair, window } => to_binary(&query::vwap(deps, env, pair, window)?),
+        QueryMsg::SimulateAccept {
+            deposit_id,
+            taker,
+            offer_asset,
+        } => to_binary(&query::simulate_accept(
+            deps,
+            env,
+            deposit_id,
+            taker,
+            offer_asset,
+        )?),
+        QueryMsg::Solvency {} => to_binary(&query::solvency(deps, &env.contract.address)?),
     }
 }
 
 mod query {
     use cosmwasm_std::StdError;
+    use cw_storage_plus::Bound;
 
-    use crate::msg::{DepositByIdResponse, DepositsBySenderResponse};
+    use crate::msg::{
+        AccruedFeesResponse, DepositByIdResponse, DepositsBySenderResponse, FeeTierResponse,
+        ReferralRewardsResponse,
+    };
     use crate::state::get_deposits;
 
     use super::*;
 
-    pub fn deposits_by_sender(deps: Deps, address: String) -> StdResult<DepositsBySenderResponse> {
-        let address = deps.api.addr_validate(&address)?;
-        Ok(DepositsBySenderResponse {
-            deposits: get_deposits(deps.storage, &address)?,
+    pub fn deposits_by_sender(deps: Deps, address: String) -> StdResult<DepositsBySenderResponse> {
+        let address = deps.api.addr_validate(&address)?;
+        let deposits = get_deposits(deps.storage, &address)?;
+        Ok(DepositsBySenderResponse {
+            token_metadata: deposits_token_metadata(
+                deps,
+                deposits.iter().map(|(_, deposit)| deposit),
+            )?,
+            deposits,
+        })
+    }
+
+    /// Looks up cached metadata of cw20 tokens deposited or asked for in the deposits
+    fn deposits_token_metadata<'a>(
+        deps: Deps,
+        deposits: impl IntoIterator<Item = &'a Deposit>,
+    ) -> StdResult<Vec<(Addr, TokenMetadata)>> {
+        let tokens: BTreeSet<Addr> = deposits
+            .into_iter()
+            .flat_map(|deposit| [&deposit.deposit.denom, &deposit.offer.exchange.denom])
+            .filter_map(|asset| match asset {
+                AssetType::Cw20(address) => Some(Addr::unchecked(address)),
+                AssetType::Native(_) => None,
+            })
+            .collect();
+        tokens
+            .into_iter()
+            .filter_map(|token| {
+                TOKEN_METADATA
+                    .may_load(deps.storage, &token)
+                    .transpose()
+                    .map(|metadata| Ok((token, metadata?)))
+            })
+            .collect()
+    }
+
+    pub fn fee_tier(deps: Deps, address: String) -> StdResult<FeeTierResponse> {
+        let address = deps.api.addr_validate(&address)?;
+        let (volume, tier, service_fee) = super::fee_tier(deps.storage, &address)?;
+        Ok(FeeTierResponse {
+            volume,
+            tier,
+            service_fee,
+        })
+    }
+
+    pub fn referral_rewards(deps: Deps, address: String) -> StdResult<ReferralRewardsResponse> {
+        let address = deps.api.addr_validate(&address)?;
+        Ok(ReferralRewardsResponse {
+            rewards: get_referral_rewards(deps.storage, &address)?,
+        })
+    }
+
+    // settings for pagination
+    const MAX_LIMIT: u32 = 30;
+    const DEFAULT_LIMIT: u32 = 10;
+
+    pub fn asset_registry(
+        deps: Deps,
+        start_after: Option<AssetType>,
+        limit: Option<u32>,
+    ) -> StdResult<AssetRegistryResponse> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+        Ok(AssetRegistryResponse {
+            allowlist_only: ALLOWLIST_ONLY.load(deps.storage)?,
+            assets: get_asset_registry(deps.storage, start_after, limit)?,
+        })
+    }
+
+    pub fn trade_history(
+        deps: Deps,
+        filter: Option<TradeHistoryFilter>,
+        start_after: Option<ID>,
+        limit: Option<u32>,
+    ) -> StdResult<TradeHistoryResponse> {
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+        let start = start_after.map(Bound::exclusive);
+        let fills = fills();
+        let range = match filter {
+            None => fills.range(deps.storage, start, None, Order::Ascending),
+            Some(TradeHistoryFilter::Maker { address }) => fills
+                .idx
+                .maker
+                .prefix(deps.api.addr_validate(&address)?)
+                .range(deps.storage, start, None, Order::Ascending),
+            Some(TradeHistoryFilter::Taker { address }) => fills
+                .idx
+                .taker
+                .prefix(deps.api.addr_validate(&address)?)
+                .range(deps.storage, start, None, Order::Ascending),
+            Some(TradeHistoryFilter::Pair(pair)) => {
+                fills
+                    .idx
+                    .pair
+                    .prefix(pair.key())
+                    .range(deps.storage, start, None, Order::Ascending)
+            }
+        };
+
+        Ok(TradeHistoryResponse {
+            fills: range
+                .take(limit)
+                .map(|item| Ok(item?.1))
+                .collect::<StdResult<_>>()?,
+        })
+    }
+
+    pub fn pair_stats(deps: Deps, pair: Pair) -> StdResult<PairStats> {
+        let (deposit_key, exchange_key) = pair.key();
+        PAIR_STATS.load(deps.storage, (&deposit_key, &exchange_key))
+    }
+
+    pub fn vwap(deps: Deps, env: Env, pair: Pair, window: u64) -> StdResult<VwapResponse> {
+        if window == 0 {
+            return Err(StdError::generic_err("VWAP window must be positive"));
+        }
+        let (deposit_key, exchange_key) = pair.key();
+        let stats = PAIR_STATS.load(deps.storage, (&deposit_key, &exchange_key))?;
+        let end = env.block.time;
+        let start = Timestamp::from_seconds(end.seconds().saturating_sub(window));
+
+        // Last observation before the window tells the price at its start; if the pair
+        // wasn't traded back then, average since its first fill instead
+        let observations = PRICE_OBSERVATIONS.prefix((&deposit_key, &exchange_key));
+        let before_start = observations
+            .range(
+                deps.storage,
+                None,
+                Some(Bound::inclusive(start.seconds())),
+                Order::Descending,
+            )
+            .next()
+            .transpose()?;
+        let (observed, observation) = match before_start {
+            Some(observation) => observation,
+            None => observations
+                .range(deps.storage, None, None, Order::Ascending)
+                .next()
+                .transpose()?
+                .ok_or_else(|| StdError::not_found("PriceObservation"))?,
+        };
+        let observed = Timestamp::from_seconds(observed);
+        let start = start.max(observed);
+
+        let weight_start = volume_seconds(observation.volume, observed, start);
+        let price_start = observation.price_cumulative + observation.price * weight_start;
+        let volume_start = observation.volume_cumulative + weight_start;
+        let volume = stats.volume_cumulative_at(end) - volume_start;
+        let price = if volume.is_zero() {
+            stats.last_price
+        } else {
+            (stats.price_cumulative_at(end) - price_start) / volume
+        };
+        Ok(VwapResponse { price, start, end })
+    }
+
+    pub fn simulate_accept(
+        deps: Deps,
+        env: Env,
+        deposit_id: ID,
+        taker: String,
+        offer_asset: Asset,
+    ) -> StdResult<SimulateAcceptResponse> {
+        let taker = deps.api.addr_validate(&taker)?;
+        let quote = match execute::quote_fill(deps, &env, &taker, deposit_id, &offer_asset) {
+            Ok(quote) => quote,
+            Err(err) => {
+                return Ok(SimulateAcceptResponse {
+                    error: Some(err.to_string()),
+                    maker_receives: vec![],
+                    taker_receives: vec![],
+                    fee: None,
+                })
+            }
+        };
+
+        let mut maker_receives = vec![quote.exchange];
+        maker_receives.extend(quote.deposit.bond);
+        let mut taker_receives = vec![quote.taker_asset];
+        if !quote.surplus.is_zero() {
+            taker_receives.push(Asset {
+                denom: offer_asset.denom,
+                amount: quote.surplus,
+            });
+        }
+        Ok(SimulateAcceptResponse {
+            error: None,
+            maker_receives,
+            taker_receives,
+            fee: Some(Asset {
+                denom: quote.deposit.deposit.denom,
+                amount: quote.fee_amount,
+            }),
+        })
+    }
+
+    pub fn token_metadata(deps: Deps, address: String) -> StdResult<TokenMetadata> {
+        let address = deps.api.addr_validate(&address)?;
+        TOKEN_METADATA.load(deps.storage, &address)
+    }
+
+    pub fn anti_spam_config(deps: Deps) -> StdResult<AntiSpamConfigResponse> {
+        let config = ANTI_SPAM_CONFIG.load(deps.storage)?;
+        Ok(AntiSpamConfigResponse {
+            max_offers_per_address: config.max_offers_per_address,
+            offer_bond: config.offer_bond,
+            keeper_bounty: config.keeper_bounty,
+            min_deposits: MIN_DEPOSITS
+                .range(deps.storage, None, None, Order::Ascending)
+                .map(|item| Ok(item?.1))
+                .collect::<StdResult<_>>()?,
+        })
+    }
+
+    pub fn solvency(deps: Deps, contract: &Addr) -> StdResult<SolvencyResponse> {
+        let assets = get_liabilities(deps.storage)?
+            .into_iter()
+            .map(|liability| {
+                let balance = match &liability.denom {
+                    AssetType::Native(denom) => deps.querier.query_balance(contract, denom)?.amount,
+                    AssetType::Cw20(address) => {
+                        query_cw20_balance(deps, &Addr::unchecked(address), contract)?
+                    }
+                };
+                Ok(AssetSolvency {
+                    shortfall: liability.amount.saturating_sub(balance),
+                    asset: liability.denom,
+                    liabilities: liability.amount,
+                    balance,
+                })
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(SolvencyResponse {
+            solvent: assets.iter().all(|asset| asset.shortfall.is_zero()),
+            assets,
+        })
+    }
+
+    pub fn accrued_fees(deps: Deps) -> StdResult<AccruedFeesResponse> {
+        Ok(AccruedFeesResponse {
+            fees: get_accrued_fees(deps.storage)?,
         })
     }
 
@@ -356,9 +2669,11 @@ mod query {
             });
         }
 
+        let (sender, deposit) = deposit.into_iter().next().unwrap();
         Ok(DepositByIdResponse {
-            sender: deposit[0].0.clone(),
-            deposit: deposit[0].1.clone(),
+            token_metadata: deposits_token_metadata(deps, [&deposit])?,
+            sender,
+            deposit,
         })
     }
 }
@@ -367,96 +2682,2061 @@ mod query {
 mod tests {
     use super::*;
 
-    use cosmwasm_std::CosmosMsg;
+    use cosmwasm_std::testing::{
+        mock_dependencies_with_balance, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+        MOCK_CONTRACT_ADDR,
+    };
+    use cosmwasm_std::{
+        from_binary, Attribute, Coin, ContractResult, CosmosMsg, Decimal256, OwnedDeps, ReplyOn,
+        SubMsgResponse, SubMsgResult, SystemError, SystemResult, WasmQuery,
+    };
+
+    use crate::msg::{AccruedFeesResponse, FeeTierResponse, ReferralRewardsResponse};
+    use crate::state::FeeTier;
+
+    #[test]
+    fn exchange_messages() {
+        let deposit = (
+            Addr::unchecked("first"),
+            Asset::new_native(100_000u128, "ujuno"),
+        );
+        let exchange = (
+            Addr::unchecked("second"),
+            Asset::new_native(200_000u128, "uusdc"),
+        );
+
+        let exchanges =
+            execute::create_exchange_messages(&deposit.0, &exchange.1, &exchange.0, &deposit.1)
+                .unwrap();
+
+        assert_eq!(
+            exchanges,
+            vec![
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "first".to_owned(),
+                    amount: coins(200_000u128, "uusdc")
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "second".to_owned(),
+                    amount: coins(100_000u128, "ujuno")
+                })
+            ]
+        );
+
+        let exchange = (
+            Addr::unchecked("second"),
+            Asset::new_cw20(200_000u128, "tokenaddress"),
+        );
+
+        let exchanges =
+            execute::create_exchange_messages(&deposit.0, &exchange.1, &exchange.0, &deposit.1)
+                .unwrap();
+
+        assert_eq!(
+            exchanges,
+            vec![
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: "tokenaddress".to_owned(),
+                    msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                        recipient: "first".to_owned(),
+                        amount: 200_000u128.into()
+                    })
+                    .unwrap(),
+                    funds: vec![]
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "second".to_owned(),
+                    amount: coins(100_000u128, "ujuno")
+                })
+            ]
+        );
+
+        let deposit = (
+            Addr::unchecked("first"),
+            Asset::new_cw20(100_000u128, "othertoken"),
+        );
+
+        let exchanges =
+            execute::create_exchange_messages(&deposit.0, &exchange.1, &exchange.0, &deposit.1)
+                .unwrap();
+
+        assert_eq!(
+            exchanges,
+            vec![
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: "tokenaddress".to_owned(),
+                    msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                        recipient: "first".to_owned(),
+                        amount: 200_000u128.into()
+                    })
+                    .unwrap(),
+                    funds: vec![]
+                }),
+                CosmosMsg::Wasm(WasmMsg::Execute {
+                    contract_addr: "othertoken".to_owned(),
+                    msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                        recipient: "second".to_owned(),
+                        amount: 100_000u128.into()
+                    })
+                    .unwrap(),
+                    funds: vec![]
+                }),
+            ]
+        );
+    }
+
+    /// Mock dependencies with the contract funded well enough for the funds sent in tests
+    /// to pass the `solvency-check` assertions, as the mock bank doesn't move them
+    fn mock_dependencies() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        mock_dependencies_with_balance(
+            &["uatom", "ujuno", "uosmo", "uusdc"].map(|denom| coin(1_000_000_000, denom)),
+        )
+    }
+
+    fn default_instantiate(deps: DepsMut) {
+        instantiate(
+            deps,
+            mock_env(),
+            mock_info("creator", &[]),
+            InstantiateMsg {
+                admin: Some("admin".to_owned()),
+                fee_recipients: fee_recipients(),
+                volume_config: None,
+                referral_share: None,
+                staking_config: None,
+            },
+        )
+        .unwrap();
+    }
+
+    fn fee_recipients() -> Vec<FeeShare> {
+        vec![FeeShare {
+            address: "fees".to_owned(),
+            weight: 10_000,
+        }]
+    }
+
+    fn accrued_fees(deps: Deps) -> Vec<Asset> {
+        let res: AccruedFeesResponse =
+            from_binary(&query(deps, mock_env(), QueryMsg::AccruedFees {}).unwrap()).unwrap();
+        res.fees
+    }
+
+    fn volume_config() -> VolumeConfig {
+        VolumeConfig {
+            reference_denom: AssetType::Native("uusdc".to_owned()),
+            tiers: vec![
+                FeeTier {
+                    min_volume: Uint128::new(1_000),
+                    service_fee: Decimal::permille(5),
+                },
+                FeeTier {
+                    min_volume: Uint128::new(10_000),
+                    service_fee: Decimal::zero(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn invalid_fee_tiers() {
+        let mut deps = mock_dependencies();
+        let mut config = volume_config();
+        config.tiers.reverse();
+
+        let err = instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            InstantiateMsg {
+                admin: None,
+                fee_recipients: fee_recipients(),
+                volume_config: Some(config),
+                referral_share: None,
+                staking_config: None,
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::InvalidFeeTiers {}));
+    }
+
+    #[test]
+    fn volume_lowers_service_fee() {
+        let mut deps = mock_dependencies();
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            InstantiateMsg {
+                admin: None,
+                fee_recipients: fee_recipients(),
+                volume_config: Some(volume_config()),
+                referral_share: None,
+                staking_config: None,
+            },
+        )
+        .unwrap();
+
+        let fee_tier = |deps: Deps, address: &str| -> FeeTierResponse {
+            from_binary(
+                &query(
+                    deps,
+                    mock_env(),
+                    QueryMsg::FeeTier {
+                        address: address.to_owned(),
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            fee_tier(deps.as_ref(), "taker"),
+            FeeTierResponse {
+                volume: Uint128::zero(),
+                tier: None,
+                service_fee: Decimal::percent(1),
+            }
+        );
+
+        for _ in 0..2 {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("maker", &coins(100_000, "ujuno")),
+                ExecuteMsg::Deposit {
+                    exchange: Asset::new_native(1_000, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                },
+            )
+            .unwrap();
+        }
+
+        // First exchange is charged with base fee of 1%
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taker", &coins(1_000, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages.into_iter().map(|m| m.msg).collect::<Vec<_>>(),
+            vec![
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "maker".to_owned(),
+                    amount: coins(1_000, "uusdc")
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "taker".to_owned(),
+                    amount: coins(99_000, "ujuno")
+                }),
+            ]
+        );
+        assert_eq!(
+            accrued_fees(deps.as_ref()),
+            vec![Asset::new_native(1_000, "ujuno")]
+        );
+
+        // Filled deposit cannot be accepted again
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taker", &coins(1_000, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            fee_tier(deps.as_ref(), "taker"),
+            FeeTierResponse {
+                volume: Uint128::new(1_000),
+                tier: Some(0),
+                service_fee: Decimal::permille(5),
+            }
+        );
+        assert_eq!(fee_tier(deps.as_ref(), "maker").tier, Some(0));
+
+        // Second exchange is charged with discounted fee
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taker", &coins(1_000, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 1,
+                referrer: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            accrued_fees(deps.as_ref()),
+            vec![Asset::new_native(1_500, "ujuno")]
+        );
+
+        // Governance can replace the tiers, with the same validation
+        let update_tiers = |deps: DepsMut, volume_config| {
+            sudo(
+                deps,
+                mock_env(),
+                SudoMsg::UpdateFeeConfig {
+                    fee_recipients: None,
+                    service_fee: None,
+                    referral_share: None,
+                    volume_config: Some(volume_config),
+                },
+            )
+        };
+        let mut config = volume_config();
+        config.tiers.reverse();
+        let err = update_tiers(deps.as_mut(), config).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidFeeTiers {}));
+        let mut config = volume_config();
+        config.tiers[0].min_volume = Uint128::new(5_000);
+        update_tiers(deps.as_mut(), config).unwrap();
+        assert_eq!(
+            fee_tier(deps.as_ref(), "taker"),
+            FeeTierResponse {
+                volume: Uint128::new(2_000),
+                tier: None,
+                service_fee: Decimal::percent(1),
+            }
+        );
+    }
+
+    #[test]
+    fn referrers_share_service_fee() {
+        let mut deps = mock_dependencies();
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            InstantiateMsg {
+                admin: None,
+                fee_recipients: fee_recipients(),
+                volume_config: None,
+                referral_share: Some(Decimal::percent(20)),
+                staking_config: None,
+            },
+        )
+        .unwrap();
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &coins(100_000, "ujuno")),
+            ExecuteMsg::Deposit {
+                exchange: Asset::new_native(1_000, "uusdc"),
+                from: None,
+                referrer: Some("maker".to_owned()),
+                expires: None,
+            },
+        )
+        .unwrap_err();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &coins(100_000, "ujuno")),
+            ExecuteMsg::Deposit {
+                exchange: Asset::new_native(1_000, "uusdc"),
+                from: None,
+                referrer: Some("referrer".to_owned()),
+                expires: None,
+            },
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taker", &coins(1_000, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: Some("referrer".to_owned()),
+            },
+        )
+        .unwrap();
+        // 1_000 fee, 200 credited for each side referred
+        assert_eq!(
+            accrued_fees(deps.as_ref()),
+            vec![Asset::new_native(600, "ujuno")]
+        );
+
+        let rewards: ReferralRewardsResponse = from_binary(
+            &query(
+                deps.as_ref(),
+                mock_env(),
+                QueryMsg::ReferralRewards {
+                    address: "referrer".to_owned(),
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(rewards.rewards, vec![Asset::new_native(400, "ujuno")]);
+
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("referrer", &[]),
+            ExecuteMsg::ClaimReferralRewards {},
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "referrer".to_owned(),
+                amount: coins(400, "ujuno")
+            })
+        );
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("referrer", &[]),
+            ExecuteMsg::ClaimReferralRewards {},
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::NoReferralRewards {}));
+    }
+
+    #[test]
+    fn claim_accrued_fees() {
+        let mut deps = mock_dependencies();
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            InstantiateMsg {
+                admin: None,
+                fee_recipients: fee_recipients(),
+                volume_config: None,
+                referral_share: None,
+                staking_config: None,
+            },
+        )
+        .unwrap();
+
+        for (deposit, exchange) in [
+            (Asset::new_native(100_000, "ujuno"), "uusdc"),
+            (Asset::new_native(200_000, "uatom"), "uusdc"),
+        ] {
+            execute::deposit(
+                deps.as_mut(),
+                mock_env(),
+                Addr::unchecked("maker"),
+                deposit,
+                None,
+                execute::Ask::Fixed(Asset::new_native(1_000, exchange)),
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        }
+        for deposit_id in 0..2 {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("taker", &coins(1_000, "uusdc")),
+                ExecuteMsg::AcceptExchange {
+                    deposit_id,
+                    referrer: None,
+                },
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            accrued_fees(deps.as_ref()),
+            vec![
+                Asset::new_native(2_000, "uatom"),
+                Asset::new_native(1_000, "ujuno")
+            ]
+        );
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taker", &[]),
+            ExecuteMsg::ClaimFees { assets: None },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("fees", &[]),
+            ExecuteMsg::ClaimFees {
+                assets: Some(vec![
+                    AssetType::Native("ujuno".to_owned()),
+                    AssetType::Native("ujuno".to_owned()),
+                ]),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages.into_iter().map(|m| m.msg).collect::<Vec<_>>(),
+            vec![CosmosMsg::Bank(BankMsg::Send {
+                to_address: "fees".to_owned(),
+                amount: coins(1_000, "ujuno")
+            })]
+        );
+        assert_eq!(
+            accrued_fees(deps.as_ref()),
+            vec![Asset::new_native(2_000, "uatom")]
+        );
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("fees", &[]),
+            ExecuteMsg::ClaimFees {
+                assets: Some(vec![AssetType::Native("ujuno".to_owned())]),
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::NoFeesToClaim {}));
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("fees", &[]),
+            ExecuteMsg::ClaimFees { assets: None },
+        )
+        .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("fees", &[]),
+            ExecuteMsg::ClaimFees { assets: None },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::NoFeesToClaim {}));
+    }
+
+    #[test]
+    fn fee_distribution() {
+        let recipients = vec![
+            FeeRecipient {
+                address: Addr::unchecked("treasury"),
+                weight: 6_000,
+            },
+            FeeRecipient {
+                address: Addr::unchecked("dev"),
+                weight: 3_333,
+            },
+            FeeRecipient {
+                address: Addr::unchecked("staking"),
+                weight: 667,
+            },
+        ];
+
+        let msgs =
+            execute::fee_distribution_messages(&recipients, &Asset::new_native(1_001, "ujuno"))
+                .unwrap();
+        assert_eq!(
+            msgs,
+            vec![
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "treasury".to_owned(),
+                    amount: coins(602, "ujuno")
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "dev".to_owned(),
+                    amount: coins(333, "ujuno")
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "staking".to_owned(),
+                    amount: coins(66, "ujuno")
+                }),
+            ]
+        );
+
+        let mut deps = mock_dependencies();
+        let err = instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            InstantiateMsg {
+                admin: None,
+                fee_recipients: vec![
+                    FeeShare {
+                        address: "treasury".to_owned(),
+                        weight: 6_000,
+                    },
+                    FeeShare {
+                        address: "dev".to_owned(),
+                        weight: 3_000,
+                    },
+                ],
+                volume_config: None,
+                referral_share: None,
+                staking_config: None,
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::InvalidFeeRecipients {}));
+    }
+
+    #[test]
+    fn pause_operations() {
+        let mut deps = mock_dependencies();
+        instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            InstantiateMsg {
+                admin: Some("admin".to_owned()),
+                fee_recipients: fee_recipients(),
+                volume_config: None,
+                referral_share: None,
+                staking_config: None,
+            },
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &coins(100_000, "ujuno")),
+            ExecuteMsg::Deposit {
+                exchange: Asset::new_native(1_000, "uusdc"),
+                from: None,
+                referrer: None,
+                expires: None,
+            },
+        )
+        .unwrap();
+
+        let pause = ExecuteMsg::SetPause {
+            deposits: Some(true),
+            acceptances: Some(true),
+            withdrawals: None,
+        };
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &[]),
+            pause.clone(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), pause).unwrap();
+
+        let pause_state: PauseState =
+            from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::PauseState {}).unwrap())
+                .unwrap();
+        assert_eq!(
+            pause_state,
+            PauseState {
+                deposits: true,
+                acceptances: true,
+                withdrawals: false,
+            }
+        );
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &coins(100_000, "ujuno")),
+            ExecuteMsg::Deposit {
+                exchange: Asset::new_native(1_000, "uusdc"),
+                from: None,
+                referrer: None,
+                expires: None,
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Deposits are paused");
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taker", &coins(1_000, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Acceptances are paused");
+
+        // Makers can still get their funds back
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &[]),
+            ExecuteMsg::Withdraw { id: Some(0) },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn asset_registry() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+
+        let juno = AssetType::Native("ujuno".to_owned());
+        let usdc = AssetType::Native("uusdc".to_owned());
+        let scam = AssetType::Cw20("scamtoken".to_owned());
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            ExecuteMsg::UpdateAssetRegistry {
+                allow: vec![juno.clone(), usdc.clone()],
+                block: vec![scam.clone()],
+                remove: vec![],
+                allowlist_only: None,
+            },
+        )
+        .unwrap();
+
+        let err = execute::deposit(
+            deps.as_mut(),
+            mock_env(),
+            Addr::unchecked("maker"),
+            Asset::new_cw20(100_000, "scamtoken"),
+            None,
+            execute::Ask::Fixed(Asset::new_native(1_000, "uusdc")),
+            None,
+            None,
+            None,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Asset scamtoken is not permitted to be traded"
+        );
+        // Assets not in the registry are permitted until allowlist only mode is enabled
+        execute::deposit(
+            deps.as_mut(),
+            mock_env(),
+            Addr::unchecked("maker"),
+            Asset::new_native(100_000, "uatom"),
+            None,
+            execute::Ask::Fixed(Asset::new_native(1_000, "uusdc")),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            ExecuteMsg::UpdateAssetRegistry {
+                allow: vec![],
+                block: vec![],
+                remove: vec![scam],
+                allowlist_only: Some(true),
+            },
+        )
+        .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taker", &coins(1_000, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::AssetNotPermitted { .. }));
+
+        let registry = |start_after: Option<AssetType>| -> AssetRegistryResponse {
+            from_binary(
+                &query(
+                    deps.as_ref(),
+                    mock_env(),
+                    QueryMsg::AssetRegistry {
+                        start_after,
+                        limit: Some(1),
+                    },
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        };
+        let page = registry(None);
+        assert!(page.allowlist_only);
+        assert_eq!(
+            page.assets,
+            vec![AssetRegistryEntry {
+                asset: juno.clone(),
+                listing: AssetListing::Allowed,
+            }]
+        );
+        assert_eq!(
+            registry(Some(juno)).assets,
+            vec![AssetRegistryEntry {
+                asset: usdc.clone(),
+                listing: AssetListing::Allowed,
+            }]
+        );
+        assert_eq!(registry(Some(usdc)).assets, vec![]);
+    }
+
+    #[test]
+    fn cw20_validated_on_deposit() {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == "token" => {
+                SystemResult::Ok(ContractResult::Ok(
+                    to_binary(&TokenInfoResponse {
+                        name: "Token".to_owned(),
+                        symbol: "TKN".to_owned(),
+                        decimals: 6,
+                        total_supply: Uint128::new(1_000_000),
+                    })
+                    .unwrap(),
+                ))
+            }
+            _ => SystemResult::Err(SystemError::NoSuchContract {
+                addr: "unknown".to_owned(),
+            }),
+        });
+        default_instantiate(deps.as_mut());
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &coins(100_000, "ujuno")),
+            ExecuteMsg::Deposit {
+                exchange: Asset::new_cw20(1_000, "notatoken"),
+                from: None,
+                referrer: None,
+                expires: None,
+            },
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Address notatoken is not a cw20 token contract"
+        );
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &coins(100_000, "ujuno")),
+            ExecuteMsg::Deposit {
+                exchange: Asset::new_cw20(1_000, "token"),
+                from: None,
+                referrer: None,
+                expires: None,
+            },
+        )
+        .unwrap();
+
+        let metadata: TokenMetadata = from_binary(
+            &query(
+                deps.as_ref(),
+                mock_env(),
+                QueryMsg::TokenMetadata {
+                    address: "token".to_owned(),
+                },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            metadata,
+            TokenMetadata {
+                name: "Token".to_owned(),
+                symbol: "TKN".to_owned(),
+                decimals: 6,
+            }
+        );
+
+        // Deposit queries carry metadata of the tokens involved
+        let token_metadata = vec![(Addr::unchecked("token"), metadata)];
+        assert_eq!(
+            query::deposit_by_id(deps.as_ref(), 0)
+                .unwrap()
+                .token_metadata,
+            token_metadata
+        );
+        assert_eq!(
+            query::deposits_by_sender(deps.as_ref(), "maker".to_owned())
+                .unwrap()
+                .token_metadata,
+            token_metadata
+        );
+    }
+
+    #[test]
+    fn strict_native_funds() {
+        let mut deps = mock_dependencies();
+        let err = instantiate(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("creator", &coins(100, "ujuno")),
+            InstantiateMsg {
+                admin: None,
+                fee_recipients: fee_recipients(),
+                volume_config: None,
+                referral_share: None,
+                staking_config: None,
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::NonPayable {}));
+        default_instantiate(deps.as_mut());
+
+        let deposit = |deps: DepsMut, funds: &[Coin]| {
+            execute(
+                deps,
+                mock_env(),
+                mock_info("maker", funds),
+                ExecuteMsg::Deposit {
+                    exchange: Asset::new_native(1_000, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                },
+            )
+        };
+        let err = deposit(deps.as_mut(), &[]).unwrap_err();
+        assert!(matches!(err, ContractError::NoFundsWithDeposit {}));
+        let err = deposit(deps.as_mut(), &coins(0, "ujuno")).unwrap_err();
+        assert!(matches!(err, ContractError::ZeroAmount {}));
+        let err =
+            deposit(deps.as_mut(), &[coin(100_000, "ujuno"), coin(100, "uatom")]).unwrap_err();
+        assert!(matches!(err, ContractError::MultipleDenoms {}));
+        deposit(deps.as_mut(), &coins(100_000, "ujuno")).unwrap();
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &coins(100, "ujuno")),
+            ExecuteMsg::Withdraw { id: None },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::NonPayable {}));
+
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("token", &[]),
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: "taker".to_owned(),
+                amount: Uint128::zero(),
+                msg: to_binary(&ReceiveCw20Msg::AcceptExchange {
+                    deposit_id: 0,
+                    referrer: None,
+                })
+                .unwrap(),
+            }),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::ZeroAmount {}));
+    }
 
     #[test]
-    fn exchange_messages() {
-        let deposit = (
-            Addr::unchecked("first"),
-            Asset::new_native(100_000u128, "ujuno"),
-        );
-        let exchange = (
-            Addr::unchecked("second"),
-            Asset::new_native(200_000u128, "uusdc"),
-        );
+    fn overpayment_is_refunded() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &coins(100_000, "ujuno")),
+            ExecuteMsg::Deposit {
+                exchange: Asset::new_native(1_000, "uusdc"),
+                from: None,
+                referrer: None,
+                expires: None,
+            },
+        )
+        .unwrap();
 
-        let exchanges =
-            execute::create_exchange_messages(&deposit.0, &exchange.1, &exchange.0, &deposit.1)
-                .unwrap();
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taker", &coins(999, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::ExchangeIncorrectAmount { .. }));
 
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taker", &coins(1_250, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+        )
+        .unwrap();
         assert_eq!(
-            exchanges,
+            res.messages.into_iter().map(|m| m.msg).collect::<Vec<_>>(),
             vec![
                 CosmosMsg::Bank(BankMsg::Send {
-                    to_address: "first".to_owned(),
-                    amount: coins(200_000u128, "uusdc")
+                    to_address: "maker".to_owned(),
+                    amount: coins(1_000, "uusdc")
                 }),
                 CosmosMsg::Bank(BankMsg::Send {
-                    to_address: "second".to_owned(),
-                    amount: coins(100_000u128, "ujuno")
-                })
+                    to_address: "taker".to_owned(),
+                    amount: coins(99_000, "ujuno")
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "taker".to_owned(),
+                    amount: coins(250, "uusdc")
+                }),
             ]
         );
+    }
 
-        let exchange = (
-            Addr::unchecked("second"),
-            Asset::new_cw20(200_000u128, "tokenaddress"),
+    fn mock_token_balance(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, balance: u128) {
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { msg, .. } => {
+                let response = match from_binary(msg).unwrap() {
+                    Cw20QueryMsg::Balance { .. } => to_binary(&BalanceResponse {
+                        balance: Uint128::new(balance),
+                    }),
+                    Cw20QueryMsg::TokenInfo {} => to_binary(&TokenInfoResponse {
+                        name: "Taxed".to_owned(),
+                        symbol: "TAX".to_owned(),
+                        decimals: 6,
+                        total_supply: Uint128::new(1_000_000),
+                    }),
+                    _ => {
+                        return SystemResult::Err(SystemError::UnsupportedRequest {
+                            kind: "cw20 query".to_owned(),
+                        })
+                    }
+                };
+                SystemResult::Ok(ContractResult::Ok(response.unwrap()))
+            }
+            _ => SystemResult::Err(SystemError::UnsupportedRequest {
+                kind: "wasm query".to_owned(),
+            }),
+        });
+    }
+
+    #[test]
+    fn balance_delta_accounting() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        mock_token_balance(&mut deps, 500);
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            ExecuteMsg::SetBalanceDeltaAccounting {
+                token: "taxed".to_owned(),
+                enabled: true,
+            },
+        )
+        .unwrap();
+
+        // Token takes 5% tax on transfer, contract receives only 950 tokens
+        mock_token_balance(&mut deps, 1_450);
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taxed", &[]),
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: "maker".to_owned(),
+                amount: Uint128::new(1_000),
+                msg: to_binary(&ReceiveCw20Msg::Deposit {
+                    exchange: Asset::new_native(1_000, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                })
+                .unwrap(),
+            }),
+        )
+        .unwrap();
+        let deposit = query::deposit_by_id(deps.as_ref(), 0).unwrap().deposit;
+        assert_eq!(deposit.deposit, Asset::new_cw20(950, "taxed"));
+        assert_eq!(
+            CW20_RESERVES
+                .load(&deps.storage, &Addr::unchecked("taxed"))
+                .unwrap(),
+            Uint128::new(1_450)
         );
 
-        let exchanges =
-            execute::create_exchange_messages(&deposit.0, &exchange.1, &exchange.0, &deposit.1)
-                .unwrap();
+        // Withdrawal reduces the reserve by transferred amount
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &[]),
+            ExecuteMsg::Withdraw { id: Some(0) },
+        )
+        .unwrap();
+        assert_eq!(
+            CW20_RESERVES
+                .load(&deps.storage, &Addr::unchecked("taxed"))
+                .unwrap(),
+            Uint128::new(500)
+        );
+
+        // Tax taken from the contract on top of the transfer is picked up once it's done,
+        // leaving reserves of other tokens alone
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            ExecuteMsg::SetBalanceDeltaAccounting {
+                token: "other".to_owned(),
+                enabled: true,
+            },
+        )
+        .unwrap();
+        assert_eq!(res.messages[0].reply_on, ReplyOn::Success);
+        mock_token_balance(&mut deps, 452);
+        let sync = |deps: DepsMut, id| {
+            reply(
+                deps,
+                mock_env(),
+                Reply {
+                    id,
+                    result: SubMsgResult::Ok(SubMsgResponse {
+                        events: vec![],
+                        data: None,
+                    }),
+                },
+            )
+        };
+        sync(deps.as_mut(), res.messages[0].id).unwrap();
+        let reserve = |deps: Deps, token: &str| {
+            CW20_RESERVES
+                .load(deps.storage, &Addr::unchecked(token))
+                .unwrap()
+        };
+        assert_eq!(reserve(deps.as_ref(), "taxed"), Uint128::new(452));
+        assert_eq!(reserve(deps.as_ref(), "other"), Uint128::new(1_450));
+        let err = sync(deps.as_mut(), res.messages[0].id).unwrap_err();
+        assert!(matches!(err, ContractError::UnknownReplyId { .. }));
+    }
+
+    #[test]
+    fn anti_spam_limits() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            ExecuteMsg::UpdateAntiSpamConfig {
+                max_offers_per_address: Some(2),
+                offer_bond: Some(coin(10, "ujuno")),
+                keeper_bounty: None,
+                min_deposits: vec![Asset::new_native(1_000, "uatom")],
+            },
+        )
+        .unwrap();
+
+        let deposit = |deps: DepsMut, funds: &[Coin]| {
+            execute(
+                deps,
+                mock_env(),
+                mock_info("maker", funds),
+                ExecuteMsg::Deposit {
+                    exchange: Asset::new_native(1_000, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                },
+            )
+        };
+        let err = deposit(deps.as_mut(), &coins(100_000, "uatom")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Offer bond of 10ujuno has to be provided with the deposit"
+        );
+        let err = deposit(deps.as_mut(), &[coin(999, "uatom"), coin(10, "ujuno")]).unwrap_err();
+        assert_eq!(err.to_string(), "Deposit is below the minimum of 1000uatom");
+
+        // Bond sent as a separate coin or included in deposit of the same denom
+        deposit(deps.as_mut(), &[coin(1_000, "uatom"), coin(10, "ujuno")]).unwrap();
+        deposit(deps.as_mut(), &coins(100_010, "ujuno")).unwrap();
+        let err = deposit(deps.as_mut(), &coins(100_010, "ujuno")).unwrap_err();
+        assert!(matches!(err, ContractError::TooManyOffers { max: 2 }));
+
+        let deposit = query::deposit_by_id(deps.as_ref(), 1).unwrap().deposit;
+        assert_eq!(deposit.deposit, Asset::new_native(100_000, "ujuno"));
+        assert_eq!(deposit.bond, Some(Asset::new_native(10, "ujuno")));
+
+        // Cw20 deposits use prepaid bonds
+        let cw20_deposit = |deps: DepsMut, sender: &str| {
+            execute(
+                deps,
+                mock_env(),
+                mock_info("token", &[]),
+                ExecuteMsg::Receive(Cw20ReceiveMsg {
+                    sender: sender.to_owned(),
+                    amount: Uint128::new(1_000),
+                    msg: to_binary(&ReceiveCw20Msg::Deposit {
+                        exchange: Asset::new_native(1_000, "uusdc"),
+                        from: None,
+                        referrer: None,
+                        expires: None,
+                    })
+                    .unwrap(),
+                }),
+            )
+        };
+        mock_token_balance(&mut deps, 1_000);
+        let err = cw20_deposit(deps.as_mut(), "other").unwrap_err();
+        assert!(matches!(err, ContractError::BondNotProvided { .. }));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &coins(20, "ujuno")),
+            ExecuteMsg::PrepayBond {},
+        )
+        .unwrap();
+        cw20_deposit(deps.as_mut(), "other").unwrap();
 
+        // Credit left in the former bond denom is returned on the next prepayment
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            ExecuteMsg::UpdateAntiSpamConfig {
+                max_offers_per_address: None,
+                offer_bond: Some(coin(10, "uatom")),
+                keeper_bounty: None,
+                min_deposits: vec![],
+            },
+        )
+        .unwrap();
+        let err = cw20_deposit(deps.as_mut(), "other").unwrap_err();
+        assert!(matches!(err, ContractError::BondNotProvided { .. }));
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &coins(10, "uatom")),
+            ExecuteMsg::PrepayBond {},
+        )
+        .unwrap();
         assert_eq!(
-            exchanges,
+            res.messages.into_iter().map(|m| m.msg).collect::<Vec<_>>(),
+            vec![CosmosMsg::Bank(BankMsg::Send {
+                to_address: "other".to_owned(),
+                amount: coins(10, "ujuno")
+            })]
+        );
+
+        // Withdrawal returns deposits, bonds and unused bond credit
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("other", &[]),
+            ExecuteMsg::Withdraw { id: None },
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages.into_iter().map(|m| m.msg).collect::<Vec<_>>(),
             vec![
                 CosmosMsg::Wasm(WasmMsg::Execute {
-                    contract_addr: "tokenaddress".to_owned(),
+                    contract_addr: "token".to_owned(),
                     msg: to_binary(&Cw20ExecuteMsg::Transfer {
-                        recipient: "first".to_owned(),
-                        amount: 200_000u128.into()
+                        recipient: "other".to_owned(),
+                        amount: 1_000u128.into()
                     })
                     .unwrap(),
                     funds: vec![]
                 }),
                 CosmosMsg::Bank(BankMsg::Send {
-                    to_address: "second".to_owned(),
-                    amount: coins(100_000u128, "ujuno")
-                })
+                    to_address: "other".to_owned(),
+                    amount: coins(10, "ujuno")
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "other".to_owned(),
+                    amount: coins(10, "uatom")
+                }),
             ]
         );
 
-        let deposit = (
-            Addr::unchecked("first"),
-            Asset::new_cw20(100_000u128, "othertoken"),
+        // Fields not specified are kept, zero values clear them
+        assert_eq!(
+            ANTI_SPAM_CONFIG.load(&deps.storage).unwrap(),
+            AntiSpamConfig {
+                max_offers_per_address: Some(2),
+                offer_bond: Some(coin(10, "uatom")),
+                keeper_bounty: Decimal::zero(),
+            }
         );
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            ExecuteMsg::UpdateAntiSpamConfig {
+                max_offers_per_address: Some(0),
+                offer_bond: Some(coin(0, "uatom")),
+                keeper_bounty: None,
+                min_deposits: vec![],
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            ANTI_SPAM_CONFIG.load(&deps.storage).unwrap(),
+            AntiSpamConfig::default()
+        );
+    }
 
-        let exchanges =
-            execute::create_exchange_messages(&deposit.0, &exchange.1, &exchange.0, &deposit.1)
-                .unwrap();
+    #[test]
+    fn sweep_expired_offers() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            ExecuteMsg::UpdateAntiSpamConfig {
+                max_offers_per_address: None,
+                offer_bond: Some(coin(100, "ujuno")),
+                keeper_bounty: Some(Decimal::percent(10)),
+                min_deposits: vec![],
+            },
+        )
+        .unwrap();
+
+        let now = mock_env().block.time;
+        let deposit = |deps: DepsMut, expires: Option<Timestamp>| {
+            execute(
+                deps,
+                mock_env(),
+                mock_info("maker", &[coin(1_000, "uatom"), coin(100, "ujuno")]),
+                ExecuteMsg::Deposit {
+                    exchange: Asset::new_native(1_000, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires,
+                },
+            )
+        };
+        let err = deposit(deps.as_mut(), Some(now)).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidExpiration {}));
+        deposit(deps.as_mut(), Some(now.plus_seconds(200))).unwrap();
+        deposit(deps.as_mut(), Some(now.plus_seconds(100))).unwrap();
+        deposit(deps.as_mut(), None).unwrap();
+
+        let mut env = mock_env();
+        env.block.time = now.plus_seconds(150);
+        let err = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("taker", &coins(1_000, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 1,
+                referrer: None,
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::OfferExpired { id: 1 }));
 
+        // Only the offer expired by now is swept
+        let res = execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("keeper", &[]),
+            ExecuteMsg::SweepExpired { limit: None },
+        )
+        .unwrap();
         assert_eq!(
-            exchanges,
+            res.messages.into_iter().map(|m| m.msg).collect::<Vec<_>>(),
             vec![
-                CosmosMsg::Wasm(WasmMsg::Execute {
-                    contract_addr: "tokenaddress".to_owned(),
-                    msg: to_binary(&Cw20ExecuteMsg::Transfer {
-                        recipient: "first".to_owned(),
-                        amount: 200_000u128.into()
-                    })
-                    .unwrap(),
-                    funds: vec![]
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "maker".to_owned(),
+                    amount: coins(1_000, "uatom")
                 }),
-                CosmosMsg::Wasm(WasmMsg::Execute {
-                    contract_addr: "othertoken".to_owned(),
-                    msg: to_binary(&Cw20ExecuteMsg::Transfer {
-                        recipient: "second".to_owned(),
-                        amount: 100_000u128.into()
-                    })
-                    .unwrap(),
-                    funds: vec![]
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "keeper".to_owned(),
+                    amount: coins(10, "ujuno")
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "maker".to_owned(),
+                    amount: coins(90, "ujuno")
+                }),
+            ]
+        );
+        assert_eq!(
+            query::deposits_by_sender(deps.as_ref(), "maker".to_owned())
+                .unwrap()
+                .deposits
+                .into_iter()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+
+        // Withdrawn offers leave the expiration index
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &[]),
+            ExecuteMsg::Withdraw { id: Some(0) },
+        )
+        .unwrap();
+        env.block.time = now.plus_seconds(1_000);
+        let res = execute(
+            deps.as_mut(),
+            env,
+            mock_info("keeper", &[]),
+            ExecuteMsg::SweepExpired { limit: None },
+        )
+        .unwrap();
+        assert!(res.messages.is_empty());
+    }
+
+    #[test]
+    fn emergency_refund() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        for (owner, denom) in [("alice", "uatom"), ("bob", "ujuno"), ("alice", "uosmo")] {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(owner, &coins(1_000, denom)),
+                ExecuteMsg::Deposit {
+                    exchange: Asset::new_native(1_000, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                },
+            )
+            .unwrap();
+        }
+
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("admin", &[]),
+            ExecuteMsg::UpdateAntiSpamConfig {
+                max_offers_per_address: None,
+                offer_bond: Some(coin(10, "ujuno")),
+                keeper_bounty: None,
+                min_deposits: vec![],
+            },
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("carol", &coins(10, "ujuno")),
+            ExecuteMsg::PrepayBond {},
+        )
+        .unwrap();
+
+        let refund = |deps: DepsMut, sender: &str| {
+            execute(
+                deps,
+                mock_env(),
+                mock_info(sender, &[]),
+                ExecuteMsg::EmergencyRefund { limit: Some(2) },
+            )
+        };
+        let err = refund(deps.as_mut(), "alice").unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let res = refund(deps.as_mut(), "admin").unwrap();
+        assert_eq!(
+            res.messages.into_iter().map(|m| m.msg).collect::<Vec<_>>(),
+            vec![
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "bob".to_owned(),
+                    amount: coins(1_000, "ujuno")
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "alice".to_owned(),
+                    amount: coins(1_000, "uatom")
+                }),
+            ]
+        );
+        assert_eq!(
+            OtcEvent::from_events(&res.events).unwrap(),
+            vec![
+                OtcEvent::Withdraw {
+                    id: 1,
+                    maker: Addr::unchecked("bob"),
+                    deposit: Asset::new_native(1_000, "ujuno"),
+                },
+                OtcEvent::Withdraw {
+                    id: 0,
+                    maker: Addr::unchecked("alice"),
+                    deposit: Asset::new_native(1_000, "uatom"),
+                },
+            ]
+        );
+        assert_eq!(res.events.len(), 4);
+        assert_eq!(
+            res.events[2],
+            Event::new("emergency_refund")
+                .add_attribute("id", "0")
+                .add_attribute("owner", "alice")
+                .add_attribute("deposit", "1000uatom")
+        );
+
+        let cancelled: CancelledOffer = from_binary(
+            &query(
+                deps.as_ref(),
+                mock_env(),
+                QueryMsg::CancelledOffer { id: 0 },
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cancelled.owner, Addr::unchecked("alice"));
+        assert_eq!(cancelled.deposit.deposit, Asset::new_native(1_000, "uatom"));
+
+        // Last deposit leaves room in the page for the bond credit
+        let res = refund(deps.as_mut(), "admin").unwrap();
+        assert_eq!(
+            res.messages.into_iter().map(|m| m.msg).collect::<Vec<_>>(),
+            vec![
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "alice".to_owned(),
+                    amount: coins(1_000, "uosmo")
+                }),
+                CosmosMsg::Bank(BankMsg::Send {
+                    to_address: "carol".to_owned(),
+                    amount: coins(10, "ujuno")
                 }),
             ]
         );
+        assert_eq!(res.events[0].attributes[0].value, "2");
+        let res = refund(deps.as_mut(), "admin").unwrap();
+        assert!(res.messages.is_empty());
+        assert!(DEPOSITS.is_empty(&deps.storage));
+        assert!(BOND_CREDITS.is_empty(&deps.storage));
+    }
+
+    #[test]
+    fn solvency() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        for (owner, amount) in [("alice", 1_000), ("bob", 500)] {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(owner, &coins(amount, "uatom")),
+                ExecuteMsg::Deposit {
+                    exchange: Asset::new_native(1_000, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                },
+            )
+            .unwrap();
+        }
+        add_accrued_fee(&mut deps.storage, &Asset::new_native(10, "uusdc")).unwrap();
+
+        let solvency = |deps: Deps| -> SolvencyResponse {
+            from_binary(&query(deps, mock_env(), QueryMsg::Solvency {}).unwrap()).unwrap()
+        };
+        deps.querier.update_balance(
+            MOCK_CONTRACT_ADDR,
+            vec![coin(1_200, "uatom"), coin(10, "uusdc")],
+        );
+        let res = solvency(deps.as_ref());
+        assert!(!res.solvent);
+        assert_eq!(
+            res.assets,
+            vec![
+                AssetSolvency {
+                    asset: AssetType::Native("uatom".to_owned()),
+                    liabilities: Uint128::new(1_500),
+                    balance: Uint128::new(1_200),
+                    shortfall: Uint128::new(300),
+                },
+                AssetSolvency {
+                    asset: AssetType::Native("uusdc".to_owned()),
+                    liabilities: Uint128::new(10),
+                    balance: Uint128::new(10),
+                    shortfall: Uint128::zero(),
+                },
+            ]
+        );
+
+        deps.querier.update_balance(
+            MOCK_CONTRACT_ADDR,
+            vec![coin(1_500, "uatom"), coin(10, "uusdc")],
+        );
+        assert!(solvency(deps.as_ref()).solvent);
+    }
+
+    #[test]
+    fn ids_returned_in_data() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        for expected_id in 0..2 {
+            let res = execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("maker", &coins(1_000, "uatom")),
+                ExecuteMsg::Deposit {
+                    exchange: Asset::new_native(500, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                },
+            )
+            .unwrap();
+            let data: DepositResponse = from_binary(&res.data.unwrap()).unwrap();
+            assert_eq!(data.id, expected_id);
+            assert!(res
+                .attributes
+                .contains(&Attribute::new("id", expected_id.to_string())));
+        }
+
+        let res = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("taker", &coins(600, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 1,
+                referrer: None,
+            },
+        )
+        .unwrap();
+        let data: FillResponse = from_binary(&res.data.unwrap()).unwrap();
+        assert_eq!(
+            data,
+            FillResponse {
+                id: 1,
+                maker: Addr::unchecked("maker"),
+                taker: Addr::unchecked("taker"),
+                paid: Asset::new_native(500, "uusdc"),
+                received: Asset::new_native(990, "uatom"),
+                fee: Asset::new_native(10, "uatom"),
+            }
+        );
+        assert!(res.attributes.contains(&Attribute::new("id", "1")));
+    }
+
+    #[test]
+    fn trade_history() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        for (maker, denom) in [("alice", "uatom"), ("bob", "ujuno"), ("alice", "ujuno")] {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(maker, &coins(1_000, denom)),
+                ExecuteMsg::Deposit {
+                    exchange: Asset::new_native(100, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                },
+            )
+            .unwrap();
+        }
+        for (taker, deposit_id) in [("carol", 2), ("dave", 0), ("carol", 1)] {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info(taker, &coins(100, "uusdc")),
+                ExecuteMsg::AcceptExchange {
+                    deposit_id,
+                    referrer: None,
+                },
+            )
+            .unwrap();
+        }
+
+        let history = |deps: Deps, filter, start_after| -> Vec<ID> {
+            query::trade_history(deps, filter, start_after, Some(2))
+                .unwrap()
+                .fills
+                .into_iter()
+                .map(|fill| fill.deposit_id)
+                .collect()
+        };
+        assert_eq!(history(deps.as_ref(), None, None), vec![0, 1]);
+        assert_eq!(history(deps.as_ref(), None, Some(1)), vec![2]);
+        let by_maker = TradeHistoryFilter::Maker {
+            address: "alice".to_owned(),
+        };
+        assert_eq!(history(deps.as_ref(), Some(by_maker), None), vec![0, 2]);
+        let by_taker = TradeHistoryFilter::Taker {
+            address: "carol".to_owned(),
+        };
+        assert_eq!(
+            history(deps.as_ref(), Some(by_taker.clone()), None),
+            vec![1, 2]
+        );
+        assert_eq!(history(deps.as_ref(), Some(by_taker), Some(1)), vec![2]);
+        let by_pair = TradeHistoryFilter::Pair(Pair {
+            deposit: AssetType::Native("ujuno".to_owned()),
+            exchange: AssetType::Native("uusdc".to_owned()),
+        });
+        assert_eq!(history(deps.as_ref(), Some(by_pair), None), vec![1, 2]);
+
+        let fill = query::trade_history(deps.as_ref(), None, None, Some(1))
+            .unwrap()
+            .fills
+            .remove(0);
+        assert_eq!(
+            fill,
+            Fill {
+                deposit_id: 0,
+                maker: Addr::unchecked("alice"),
+                taker: Addr::unchecked("dave"),
+                paid: Asset::new_native(100, "uusdc"),
+                received: Asset::new_native(990, "uatom"),
+                fee: Asset::new_native(10, "uatom"),
+                time: mock_env().block.time,
+                height: mock_env().block.height,
+            }
+        );
+    }
+
+    #[test]
+    fn pair_stats_and_vwap() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        let pair = Pair {
+            deposit: AssetType::Native("uatom".to_owned()),
+            exchange: AssetType::Native("uusdc".to_owned()),
+        };
+        let start = mock_env().block.time;
+
+        // Price is 0.1 for the first 100 seconds, then 0.2
+        for (deposit_id, (price, seconds)) in [(100, 0), (200, 100)].into_iter().enumerate() {
+            execute(
+                deps.as_mut(),
+                mock_env(),
+                mock_info("maker", &coins(1_000, "uatom")),
+                ExecuteMsg::Deposit {
+                    exchange: Asset::new_native(price, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                },
+            )
+            .unwrap();
+            let mut env = mock_env();
+            env.block.time = start.plus_seconds(seconds);
+            execute(
+                deps.as_mut(),
+                env,
+                mock_info("taker", &coins(price, "uusdc")),
+                ExecuteMsg::AcceptExchange {
+                    deposit_id: deposit_id as ID,
+                    referrer: None,
+                },
+            )
+            .unwrap();
+        }
+
+        let stats = query::pair_stats(deps.as_ref(), pair.clone()).unwrap();
+        assert_eq!(
+            stats,
+            PairStats {
+                pair: pair.clone(),
+                deposit_volume: Uint128::new(2_000),
+                exchange_volume: Uint128::new(300),
+                fills: 2,
+                last_price: Decimal256::percent(20),
+                last_volume: Uint128::new(1_000),
+                price_cumulative: Decimal256::from_ratio(10_000u32, 1u8),
+                volume_cumulative: Decimal256::from_ratio(100_000u32, 1u8),
+                last_updated: start.plus_seconds(100),
+            }
+        );
+
+        let mut env = mock_env();
+        env.block.time = start.plus_seconds(200);
+        let vwap = |window| query::vwap(deps.as_ref(), env.clone(), pair.clone(), window).unwrap();
+        assert_eq!(vwap(200).price, Decimal256::percent(15));
+        assert_eq!(vwap(50).price, Decimal256::percent(20));
+        assert_eq!(vwap(150).price, Decimal256::from_ratio(5u8, 30u8));
+        // Window reaching before the first fill is shortened
+        let res = vwap(1_000);
+        assert_eq!(res.price, Decimal256::percent(15));
+        assert_eq!(res.start.seconds(), start.seconds());
+
+        query::vwap(deps.as_ref(), env.clone(), pair.clone(), 0).unwrap_err();
+
+        // Tiny fill at price 1 is weighted by its volume, not just its duration
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("maker", &coins(10, "uatom")),
+            ExecuteMsg::Deposit {
+                exchange: Asset::new_native(10, "uusdc"),
+                from: None,
+                referrer: None,
+                expires: None,
+            },
+        )
+        .unwrap();
+        execute(
+            deps.as_mut(),
+            env.clone(),
+            mock_info("taker", &coins(10, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 2,
+                referrer: None,
+            },
+        )
+        .unwrap();
+        let mut later = env.clone();
+        later.block.time = start.plus_seconds(300);
+        assert_eq!(
+            query::vwap(deps.as_ref(), later, pair.clone(), 300)
+                .unwrap()
+                .price,
+            Decimal256::from_ratio(31u8, 201u8)
+        );
+
+        query::vwap(
+            deps.as_ref(),
+            env,
+            Pair {
+                deposit: pair.exchange,
+                exchange: pair.deposit,
+            },
+            100,
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn simulate_accept() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("maker", &coins(1_000, "uatom")),
+            ExecuteMsg::Deposit {
+                exchange: Asset::new_native(100, "uusdc"),
+                from: Some("carol".to_owned()),
+                referrer: None,
+                expires: None,
+            },
+        )
+        .unwrap();
+
+        let simulate = |deps: Deps, taker: &str, offer_asset| {
+            query::simulate_accept(deps, mock_env(), 0, taker.to_owned(), offer_asset).unwrap()
+        };
+        let res = simulate(deps.as_ref(), "carol", Asset::new_native(150, "uusdc"));
+        assert_eq!(
+            res,
+            SimulateAcceptResponse {
+                error: None,
+                maker_receives: vec![Asset::new_native(100, "uusdc")],
+                taker_receives: vec![
+                    Asset::new_native(990, "uatom"),
+                    Asset::new_native(50, "uusdc")
+                ],
+                fee: Some(Asset::new_native(10, "uatom")),
+            }
+        );
+        let res = simulate(deps.as_ref(), "carol", Asset::new_native(100, "ujuno"));
+        assert_eq!(
+            res.error.unwrap(),
+            "Offer expected tokens: uusdc, user provided tokens: ujuno"
+        );
+        let res = simulate(deps.as_ref(), "dave", Asset::new_native(100, "uusdc"));
+        assert_eq!(
+            res,
+            SimulateAcceptResponse {
+                error: Some("Offer 0 can only be accepted by carol".to_owned()),
+                maker_receives: vec![],
+                taker_receives: vec![],
+                fee: None,
+            }
+        );
+
+        // Counterparty restriction is enforced on execution as well
+        let err = execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("dave", &coins(100, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err,
+            ContractError::CounterpartyMismatch { id: 0, .. }
+        ));
+        execute(
+            deps.as_mut(),
+            mock_env(),
+            mock_info("carol", &coins(100, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn priced_offers() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        let deposit = |deps: DepsMut, price| {
+            execute(
+                deps,
+                mock_env(),
+                mock_info("maker", &coins(1_001, "uatom")),
+                ExecuteMsg::DepositAtPrice {
+                    ask: AssetType::Native("uusdc".to_owned()),
+                    price,
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                },
+            )
+        };
+        let err = deposit(deps.as_mut(), Decimal::zero()).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidPrice {}));
+        deposit(deps.as_mut(), Decimal::permille(150)).unwrap();
+
+        // 1001 * 0.15 = 150.15 is rounded up
+        let offer = query::deposit_by_id(deps.as_ref(), 0)
+            .unwrap()
+            .deposit
+            .offer;
+        assert_eq!(offer.exchange, Asset::new_native(151, "uusdc"));
+        assert_eq!(offer.price, Some(Decimal::permille(150)));
+
+        let accept = |deps: DepsMut, amount| {
+            execute(
+                deps,
+                mock_env(),
+                mock_info("taker", &coins(amount, "uusdc")),
+                ExecuteMsg::AcceptExchange {
+                    deposit_id: 0,
+                    referrer: None,
+                },
+            )
+        };
+        let err = accept(deps.as_mut(), 150).unwrap_err();
+        assert!(matches!(
+            err,
+            ContractError::ExchangeIncorrectAmount {
+                expected_amount,
+                ..
+            } if expected_amount == Uint128::new(151)
+        ));
+        let res = accept(deps.as_mut(), 151).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "maker".to_owned(),
+                amount: coins(151, "uusdc")
+            })
+        );
+    }
+
+    #[test]
+    fn dutch_auction_offers() {
+        let mut deps = mock_dependencies();
+        default_instantiate(deps.as_mut());
+        let start = mock_env().block.time;
+        let auction = |steps| DutchAuction {
+            start_price: Decimal::percent(20),
+            floor_price: Decimal::percent(10),
+            start,
+            end: start.plus_seconds(100),
+            steps,
+        };
+        let deposit = |deps: DepsMut, auction| {
+            execute(
+                deps,
+                mock_env(),
+                mock_info("maker", &coins(1_000, "uatom")),
+                ExecuteMsg::DepositDutchAuction {
+                    ask: AssetType::Native("uusdc".to_owned()),
+                    auction,
+                    from: None,
+                    referrer: None,
+                    expires: None,
+                },
+            )
+        };
+        let err = deposit(
+            deps.as_mut(),
+            DutchAuction {
+                floor_price: Decimal::percent(30),
+                ..auction(None)
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, ContractError::InvalidDutchAuction {}));
+        let err = deposit(deps.as_mut(), auction(Some(101))).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidDutchAuction {}));
+        deposit(deps.as_mut(), auction(None)).unwrap();
+        deposit(deps.as_mut(), auction(Some(4))).unwrap();
+
+        let required = |deps: Deps, deposit_id, seconds| {
+            let mut env = mock_env();
+            env.block.time = start.plus_seconds(seconds);
+            query::simulate_accept(
+                deps,
+                env,
+                deposit_id,
+                "taker".to_owned(),
+                Asset::new_native(1_000, "uusdc"),
+            )
+            .unwrap()
+            .maker_receives[0]
+                .amount
+                .u128()
+        };
+        // Linear decay
+        assert_eq!(required(deps.as_ref(), 0, 0), 200);
+        assert_eq!(required(deps.as_ref(), 0, 50), 150);
+        assert_eq!(required(deps.as_ref(), 0, 99), 101);
+        assert_eq!(required(deps.as_ref(), 0, 500), 100);
+        // Four equal drops
+        assert_eq!(required(deps.as_ref(), 1, 24), 200);
+        assert_eq!(required(deps.as_ref(), 1, 74), 150);
+        assert_eq!(required(deps.as_ref(), 1, 75), 125);
+        assert_eq!(required(deps.as_ref(), 1, 100), 100);
+
+        // Long auctions with many steps don't overflow
+        let long = DutchAuction {
+            start: Timestamp::from_seconds(0),
+            end: Timestamp::from_seconds(10_000_000_000),
+            ..auction(Some(u32::MAX))
+        };
+        assert!(long.is_valid());
+        let halfway = long.price_at(Timestamp::from_seconds(5_000_000_000));
+        assert!(halfway > Decimal::percent(15));
+        assert!(halfway < Decimal::permille(151));
+        assert_eq!(long.price_at(long.end), Decimal::percent(10));
+
+        let mut env = mock_env();
+        env.block.time = start.plus_seconds(50);
+        let res = execute(
+            deps.as_mut(),
+            env,
+            mock_info("taker", &coins(160, "uusdc")),
+            ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            CosmosMsg::Bank(BankMsg::Send {
+                to_address: "maker".to_owned(),
+                amount: coins(150, "uusdc")
+            })
+        );
     }
 }
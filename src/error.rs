@@ -9,6 +9,9 @@ pub enum ContractError {
     #[error("Unauthorized")]
     Unauthorized {},
 
+    #[error("Unknown reply ID {id}")]
+    UnknownReplyId { id: u64 },
+
     #[error("No funds provided during deposit")]
     NoFundsWithDeposit {},
 
@@ -23,4 +26,102 @@ pub enum ContractError {
         expected_amount: Uint128,
         provided_amount: Uint128,
     },
+
+    #[error("Fee tiers must be sorted by ascending volume and every fee must be lower than 100%")]
+    InvalidFeeTiers {},
+
+    #[error("Service fee must be lower than 100%")]
+    InvalidServiceFee {},
+
+    #[error("Referral share can't exceed 50% of the service fee")]
+    InvalidReferralShare {},
+
+    #[error("Address can't refer itself")]
+    SelfReferral {},
+
+    #[error("No referral rewards to claim")]
+    NoReferralRewards {},
+
+    #[error("No fees to claim")]
+    NoFeesToClaim {},
+
+    #[error("Fee recipients must be unique with weights summing up to 10000 basis points")]
+    InvalidFeeRecipients {},
+
+    #[error("Staking discounts must be sorted by ascending stake and can't exceed 100%")]
+    InvalidStakingDiscounts {},
+
+    #[error("{operation} are paused")]
+    Paused { operation: String },
+
+    #[error("Asset {asset} is not permitted to be traded")]
+    AssetNotPermitted { asset: String },
+
+    #[error("Address {address} is not a cw20 token contract")]
+    InvalidCw20 { address: String },
+
+    #[error("Only one native token can be sent with a message")]
+    MultipleDenoms {},
+
+    #[error("Zero amount of tokens has been provided")]
+    ZeroAmount {},
+
+    #[error("This message does not accept funds")]
+    NonPayable {},
+
+    #[error("Offer bond of {bond} has to be provided with the deposit")]
+    BondNotProvided { bond: String },
+
+    #[error("Address reached the limit of {max} open offers")]
+    TooManyOffers { max: u32 },
+
+    #[error("Deposit is below the minimum of {min}")]
+    DepositTooSmall { min: String },
+
+    #[error("Offer {id} has expired")]
+    OfferExpired { id: u64 },
+
+    #[error("Offer {id} can only be accepted by {from}")]
+    CounterpartyMismatch { id: u64, from: String },
+
+    #[error("Offer price must be positive")]
+    InvalidPrice {},
+
+    #[error(
+        "Auction must end after it starts, with a positive floor price not above the start price and at most one step per second"
+    )]
+    InvalidDutchAuction {},
+
+    #[error("Offer {id} is being auctioned")]
+    AuctionInProgress { id: u64 },
+
+    #[error("Auction has to end in the future, on an offer without expiration, with a positive minimum increment and an extension of at most a week")]
+    InvalidAuction {},
+
+    #[error("Auction of offer {id} has ended")]
+    AuctionEnded { id: u64 },
+
+    #[error("Auction of offer {id} hasn't ended yet")]
+    AuctionNotEnded { id: u64 },
+
+    #[error("Bid has to be at least {min}")]
+    BidTooLow { min: String },
+
+    #[error("Penalty has to be between 1% and 100% of the escrow")]
+    InvalidPenalty {},
+
+    #[error("Sender has already committed a bid")]
+    BidAlreadyCommitted {},
+
+    #[error("Escrows of bids in the previous auction of offer {id} haven't all been claimed")]
+    UnclaimedEscrows { id: u64 },
+
+    #[error("Revealed bid doesn't match the commitment")]
+    CommitmentMismatch {},
+
+    #[error("Expiration has to be in the future")]
+    InvalidExpiration {},
+
+    #[error("Keeper bounty can't exceed 100% of the bond")]
+    InvalidKeeperBounty {},
 }
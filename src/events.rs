@@ -0,0 +1,144 @@
+use cosmwasm_std::{Addr, Event, Response, StdError, StdResult, Uint128};
+
+use crate::msg::FillResponse;
+use crate::state::{Asset, AssetType, ID};
+
+pub const DEPOSIT_EVENT: &str = "otc_deposit";
+pub const FILL_EVENT: &str = "otc_fill";
+pub const WITHDRAW_EVENT: &str = "otc_withdraw";
+
+/// Events emitted for indexers; the chain prefixes their types with `wasm-`
+#[derive(Clone, Debug, PartialEq)]
+pub enum OtcEvent {
+    Deposit {
+        id: ID,
+        maker: Addr,
+        deposit: Asset,
+        exchange: Asset,
+    },
+    Fill(FillResponse),
+    Withdraw {
+        id: ID,
+        maker: Addr,
+        deposit: Asset,
+    },
+}
+
+impl From<OtcEvent> for Event {
+    fn from(event: OtcEvent) -> Self {
+        match event {
+            OtcEvent::Deposit {
+                id,
+                maker,
+                deposit,
+                exchange,
+            } => Event::new(DEPOSIT_EVENT)
+                .add_attribute("id", id.to_string())
+                .add_attribute("maker", maker)
+                .add_attributes(asset_attributes("deposit", &deposit))
+                .add_attributes(asset_attributes("exchange", &exchange)),
+            OtcEvent::Fill(fill) => Event::new(FILL_EVENT)
+                .add_attribute("id", fill.id.to_string())
+                .add_attribute("maker", fill.maker)
+                .add_attribute("taker", fill.taker)
+                .add_attributes(asset_attributes("paid", &fill.paid))
+                .add_attributes(asset_attributes("received", &fill.received))
+                .add_attributes(asset_attributes("fee", &fill.fee)),
+            OtcEvent::Withdraw { id, maker, deposit } => Event::new(WITHDRAW_EVENT)
+                .add_attribute("id", id.to_string())
+                .add_attribute("maker", maker)
+                .add_attributes(asset_attributes("deposit", &deposit)),
+        }
+    }
+}
+
+/// Denom is written as the plain denom or token address, with its kind alongside
+/// so native and cw20 assets stay distinct
+fn asset_attributes(prefix: &str, asset: &Asset) -> [(String, String); 3] {
+    let kind = match asset.denom {
+        AssetType::Native(_) => "native",
+        AssetType::Cw20(_) => "cw20",
+    };
+    [
+        (format!("{}_denom", prefix), asset.denom.to_string()),
+        (format!("{}_kind", prefix), kind.to_owned()),
+        (format!("{}_amount", prefix), asset.amount.to_string()),
+    ]
+}
+
+impl OtcEvent {
+    /// Parses an event emitted by the contract, either as found in a `Response`
+    /// or with the `wasm-` prefix added by the chain; other events yield `None`
+    pub fn parse(event: &Event) -> StdResult<Option<Self>> {
+        let ty = event.ty.strip_prefix("wasm-").unwrap_or(&event.ty);
+        let parsed = match ty {
+            DEPOSIT_EVENT => OtcEvent::Deposit {
+                id: parse_id(event)?,
+                maker: Addr::unchecked(attribute(event, "maker")?),
+                deposit: parse_asset(event, "deposit")?,
+                exchange: parse_asset(event, "exchange")?,
+            },
+            FILL_EVENT => OtcEvent::Fill(FillResponse {
+                id: parse_id(event)?,
+                maker: Addr::unchecked(attribute(event, "maker")?),
+                taker: Addr::unchecked(attribute(event, "taker")?),
+                paid: parse_asset(event, "paid")?,
+                received: parse_asset(event, "received")?,
+                fee: parse_asset(event, "fee")?,
+            }),
+            WITHDRAW_EVENT => OtcEvent::Withdraw {
+                id: parse_id(event)?,
+                maker: Addr::unchecked(attribute(event, "maker")?),
+                deposit: parse_asset(event, "deposit")?,
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(parsed))
+    }
+
+    /// Parses all contract events of a response, in order of emission
+    pub fn from_response<T>(response: &Response<T>) -> StdResult<Vec<Self>> {
+        Self::from_events(&response.events)
+    }
+
+    pub fn from_events(events: &[Event]) -> StdResult<Vec<Self>> {
+        events
+            .iter()
+            .filter_map(|event| Self::parse(event).transpose())
+            .collect()
+    }
+}
+
+fn attribute<'a>(event: &'a Event, key: &str) -> StdResult<&'a str> {
+    event
+        .attributes
+        .iter()
+        .find(|attribute| attribute.key == key)
+        .map(|attribute| attribute.value.as_str())
+        .ok_or_else(|| StdError::generic_err(format!("{} event has no {}", event.ty, key)))
+}
+
+fn parse_id(event: &Event) -> StdResult<ID> {
+    attribute(event, "id")?
+        .parse()
+        .map_err(|_| StdError::generic_err(format!("{} event has invalid id", event.ty)))
+}
+
+fn parse_asset(event: &Event, prefix: &str) -> StdResult<Asset> {
+    let denom = attribute(event, &format!("{}_denom", prefix))?.to_owned();
+    let denom = match attribute(event, &format!("{}_kind", prefix))? {
+        "native" => AssetType::Native(denom),
+        "cw20" => AssetType::Cw20(denom),
+        kind => {
+            return Err(StdError::generic_err(format!(
+                "invalid asset kind {}",
+                kind
+            )))
+        }
+    };
+    let amount = attribute(event, &format!("{}_amount", prefix))?;
+    Ok(Asset {
+        denom,
+        amount: Uint128::try_from(amount)?,
+    })
+}
@@ -1,6 +1,10 @@
 pub mod contract;
 mod error;
+pub mod events;
 pub mod msg;
 pub mod state;
 
+#[cfg(test)]
+mod multitest;
+
 pub use crate::error::ContractError;
@@ -1,12 +1,38 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::Addr;
+use cosmwasm_std::{Addr, Binary, Coin, Decimal, Decimal256, Timestamp, Uint128};
 use cw20::Cw20ReceiveMsg;
 
-use crate::state::{Asset, Deposit, ID};
+use crate::state::{
+    Asset, AssetRegistryEntry, AssetType, CancelledOffer, Deposit, DutchAuction, EnglishAuction,
+    Fill, Pair, PairStats, PauseState, SealedBidAuction, StakingDiscount, TokenMetadata,
+    UnrevealedEscrows, VolumeConfig, ID,
+};
 
 #[cw_serde]
 pub struct InstantiateMsg {
-    pub fee_address: String,
+    /// Address allowed to pause the contract, defaults to the instantiator
+    pub admin: Option<String>,
+    /// Addresses splitting collected service fees
+    pub fee_recipients: Vec<FeeShare>,
+    /// Optional volume based fee discounts; without it every fill pays the base service fee
+    pub volume_config: Option<VolumeConfig>,
+    /// Part of the service fee credited to referrers, defaults to none
+    pub referral_share: Option<Decimal>,
+    /// Optional service fee discounts for stakers of the governance token
+    pub staking_config: Option<StakingConfigMsg>,
+}
+
+#[cw_serde]
+pub struct StakingConfigMsg {
+    pub staking_contract: String,
+    pub discounts: Vec<StakingDiscount>,
+}
+
+#[cw_serde]
+pub struct FeeShare {
+    pub address: String,
+    /// Share of collected fees in basis points; all shares must sum up to 10000
+    pub weight: u16,
 }
 
 #[cw_serde]
@@ -14,21 +40,186 @@ pub enum ExecuteMsg {
     // Receive CW20 message for deposit of exchange acceptance
     Receive(Cw20ReceiveMsg),
     /// Deposit native tokens with an offer
+    /// If offer bond is required, it can be sent as a second coin or included in the deposit
+    /// when both are of the same denom
     Deposit {
         // What user expects in return
         exchange: Asset,
         // Accept offer only from this address
         from: Option<String>,
+        // Address that referred the depositor
+        referrer: Option<String>,
+        // Offer can't be accepted after this time
+        expires: Option<Timestamp>,
+    },
+    /// Deposit native tokens with an offer quoted as a price
+    /// Required amount of the `ask` asset is the deposit times the price, rounded up
+    DepositAtPrice {
+        ask: AssetType,
+        // Units of ask asset per unit of deposit
+        price: Decimal,
+        // Accept offer only from this address
+        from: Option<String>,
+        // Address that referred the depositor
+        referrer: Option<String>,
+        // Offer can't be accepted after this time
+        expires: Option<Timestamp>,
+    },
+    /// Deposit native tokens with an offer which price decays over time
+    /// Required amount of the `ask` asset is computed from the price at acceptance, rounded up
+    DepositDutchAuction {
+        ask: AssetType,
+        auction: DutchAuction,
+        // Accept offer only from this address
+        from: Option<String>,
+        // Address that referred the depositor
+        referrer: Option<String>,
+        // Offer can't be accepted after this time
+        expires: Option<Timestamp>,
     },
     /// Withdraw a deposit
-    /// If no ID specified, all sender's deposits will be withdrawn
+    /// If no ID specified, all sender's deposits and unused prepaid bonds will be withdrawn
     Withdraw {
         id: Option<ID>,
     },
     /// Accepts exchange offer of given ID, executing the transaction
+    /// Tokens sent above the expected amount are refunded
     AcceptExchange {
         deposit_id: ID,
+        referrer: Option<String>,
+    },
+    /// Sends all referral rewards accrued by the sender
+    ClaimReferralRewards {},
+    /// Distributes accrued service fees between fee recipients, callable by any recipient
+    /// If no assets specified, fees in all assets will be claimed
+    ClaimFees {
+        assets: Option<Vec<AssetType>>,
     },
+    /// Pauses or resumes given operations; only callable by admin
+    /// Operations not specified keep their current state
+    SetPause {
+        deposits: Option<bool>,
+        acceptances: Option<bool>,
+        withdrawals: Option<bool>,
+    },
+    /// Updates registry of allowed and blocked assets; only callable by admin
+    UpdateAssetRegistry {
+        allow: Vec<AssetType>,
+        block: Vec<AssetType>,
+        /// Removes assets from the registry, both allowed and blocked
+        remove: Vec<AssetType>,
+        /// If set, only allowed assets can be traded
+        allowlist_only: Option<bool>,
+    },
+    /// Enables or disables balance delta accounting of cw20 token; only callable by admin
+    /// Deposits of such tokens record the change of contract's balance instead of the amount
+    /// declared by the token, which overstates it for tokens taking a transfer tax
+    SetBalanceDeltaAccounting {
+        token: String,
+        enabled: bool,
+    },
+    /// Opens an ascending-bid auction on sender's deposit, with the amount its offer
+    /// currently requires as the reserve
+    OpenAuction {
+        deposit_id: ID,
+        /// Each bid has to exceed the best one by at least this amount; must be positive
+        min_increment: Uint128,
+        end: Timestamp,
+        /// Bids placed less than this many seconds before the end push it back to that distance;
+        /// at most a week
+        extension: u64,
+    },
+    /// Bids native tokens in an auction; the outbid bidder is refunded
+    Bid {
+        deposit_id: ID,
+    },
+    /// Opens a sealed-bid auction on sender's deposit, with the amount its offer currently
+    /// requires as the reserve; bids are committed until `commit_end` and revealed until
+    /// `reveal_end`
+    OpenSealedBidAuction {
+        deposit_id: ID,
+        commit_end: Timestamp,
+        reveal_end: Timestamp,
+        /// Part of the escrow forfeited to the maker by bidders who don't reveal a valid bid;
+        /// at least 1%
+        penalty: Decimal,
+    },
+    /// Commits a hidden bid in a sealed-bid auction, escrowing native tokens covering it
+    /// Commitment is computed by `state::sealed_bid_commitment`
+    CommitBid {
+        deposit_id: ID,
+        commitment: Binary,
+    },
+    /// Reveals a committed bid; bids not beating the best one are refunded right away
+    RevealBid {
+        deposit_id: ID,
+        amount: Uint128,
+        salt: String,
+    },
+    /// Exchanges the deposit for the best bid once the auction has ended, callable by anyone
+    /// Without any bids the deposit stays open as a regular offer
+    /// While acceptances are paused or either asset is blocked, the auction is closed with
+    /// bids refunded, leaving the deposit open as well
+    /// Sealed-bid auctions pay penalties of unrevealed bids to the maker
+    SettleAuction {
+        deposit_id: ID,
+    },
+    /// Returns escrow of a bid left unrevealed in a sealed-bid auction that has been settled
+    /// or closed, minus the penalty if the auction was settled
+    ClaimBidEscrow {
+        deposit_id: ID,
+    },
+    /// Prepays offer bonds for cw20 deposits, which can't carry native funds
+    PrepayBond {},
+    /// Updates anti-spam limits; only callable by admin
+    /// Fields not specified keep their current values
+    UpdateAntiSpamConfig {
+        /// Zero removes the limit
+        max_offers_per_address: Option<u32>,
+        /// Zero amount removes the bond
+        offer_bond: Option<Coin>,
+        /// Part of the bond of an expired offer paid to whoever sweeps it
+        keeper_bounty: Option<Decimal>,
+        /// Minimum deposit per asset; zero amount removes the minimum
+        min_deposits: Vec<Asset>,
+    },
+    /// Returns up to `limit` expired deposits to their owners
+    /// Caller receives keeper bounty out of bonds of swept offers
+    SweepExpired {
+        limit: Option<u32>,
+    },
+    /// Cancels up to `limit` offers, returning deposits and bonds to their owners, then
+    /// returns unused bond credits; only callable by admin when the contract is being
+    /// decommissioned
+    EmergencyRefund {
+        limit: Option<u32>,
+    },
+}
+
+/// Messages executed by the chain itself, through governance or a clock module
+#[cw_serde]
+pub enum SudoMsg {
+    /// Updates fee configuration; fields not specified keep their current values
+    UpdateFeeConfig {
+        fee_recipients: Option<Vec<FeeShare>>,
+        service_fee: Option<Decimal>,
+        referral_share: Option<Decimal>,
+        /// Replaces volume based fee tiers
+        volume_config: Option<VolumeConfig>,
+    },
+    /// Pauses or resumes given operations
+    /// Operations not specified keep their current state
+    SetPause {
+        deposits: Option<bool>,
+        acceptances: Option<bool>,
+        withdrawals: Option<bool>,
+    },
+    /// Returns deposits to their owner even when withdrawals are paused
+    /// If no ID specified, all deposits and unused prepaid bonds of the address are returned
+    ForceRefund { address: String, id: Option<ID> },
+    /// Periodic maintenance returning up to `limit` expired deposits with their whole bonds
+    /// Does nothing while withdrawals are paused
+    ExpireOffers { limit: Option<u32> },
 }
 
 #[cw_serde]
@@ -38,9 +229,42 @@ pub enum ReceiveCw20Msg {
         exchange: Asset,
         // Accept offer only from this address
         from: Option<String>,
+        // Address that referred the depositor
+        referrer: Option<String>,
+        // Offer can't be accepted after this time
+        expires: Option<Timestamp>,
+    },
+    DepositAtPrice {
+        ask: AssetType,
+        // Units of ask asset per unit of deposit
+        price: Decimal,
+        // Accept offer only from this address
+        from: Option<String>,
+        // Address that referred the depositor
+        referrer: Option<String>,
+        // Offer can't be accepted after this time
+        expires: Option<Timestamp>,
+    },
+    DepositDutchAuction {
+        ask: AssetType,
+        auction: DutchAuction,
+        // Accept offer only from this address
+        from: Option<String>,
+        // Address that referred the depositor
+        referrer: Option<String>,
+        // Offer can't be accepted after this time
+        expires: Option<Timestamp>,
     },
     /// Accepts exchange offer of given ID, executing the transaction
-    AcceptExchange { deposit_id: ID },
+    /// Tokens sent above the expected amount are refunded
+    AcceptExchange {
+        deposit_id: ID,
+        referrer: Option<String>,
+    },
+    /// Bids cw20 tokens in an auction; the outbid bidder is refunded
+    Bid { deposit_id: ID },
+    /// Commits a hidden bid in a sealed-bid auction, escrowing cw20 tokens covering it
+    CommitBid { deposit_id: ID, commitment: Binary },
 }
 
 #[cw_serde]
@@ -52,15 +276,196 @@ pub enum QueryMsg {
     /// Query one deposit using only its ID
     #[returns(DepositByIdResponse)]
     DepositById { id: ID },
+    /// Query cumulative traded volume of an address and the fee tier it qualifies for
+    #[returns(FeeTierResponse)]
+    FeeTier { address: String },
+    /// Query referral rewards accrued by address, per asset
+    #[returns(ReferralRewardsResponse)]
+    ReferralRewards { address: String },
+    /// Query service fees collected and not yet claimed, per asset
+    #[returns(AccruedFeesResponse)]
+    AccruedFees {},
+    /// Query which operations are currently paused
+    #[returns(PauseState)]
+    PauseState {},
+    /// Query allowed and blocked assets, paginated by asset
+    #[returns(AssetRegistryResponse)]
+    AssetRegistry {
+        start_after: Option<AssetType>,
+        limit: Option<u32>,
+    },
+    /// Query cached metadata of cw20 token used in deposits
+    #[returns(TokenMetadata)]
+    TokenMetadata { address: String },
+    /// Query anti-spam limits
+    #[returns(AntiSpamConfigResponse)]
+    AntiSpamConfig {},
+    /// Query state of the auction of given deposit
+    #[returns(EnglishAuction)]
+    Auction { deposit_id: ID },
+    /// Query state of the sealed-bid auction of given deposit
+    #[returns(SealedBidAuction)]
+    SealedBidAuction { deposit_id: ID },
+    /// Query escrows of unrevealed bids left to be claimed after a sealed-bid auction ended
+    #[returns(UnrevealedEscrows)]
+    UnrevealedEscrows { deposit_id: ID },
+    /// Query offer cancelled by an emergency refund
+    #[returns(CancelledOffer)]
+    CancelledOffer { id: ID },
+    /// Query recorded fills in order of deposit ID, optionally only of one address or pair
+    #[returns(TradeHistoryResponse)]
+    TradeHistory {
+        filter: Option<TradeHistoryFilter>,
+        start_after: Option<ID>,
+        limit: Option<u32>,
+    },
+    /// Query aggregated fills of a pair
+    #[returns(PairStats)]
+    PairStats { pair: Pair },
+    /// Query volume weighted average price of a pair over last `window` seconds
+    /// Each fill's price is weighted by its volume times the seconds it stayed the last price,
+    /// so small fills barely move it
+    #[returns(VwapResponse)]
+    Vwap { pair: Pair, window: u64 },
+    /// Query what accepting the offer with given funds would result in, without executing it
+    #[returns(SimulateAcceptResponse)]
+    SimulateAccept {
+        deposit_id: ID,
+        taker: String,
+        offer_asset: Asset,
+    },
+    /// Query whether contract's balances cover everything it owes, per asset
+    #[returns(SolvencyResponse)]
+    Solvency {},
+}
+
+/// Data returned by a deposit
+#[cw_serde]
+pub struct DepositResponse {
+    pub id: ID,
+}
+
+/// Data returned by an accepted exchange
+#[cw_serde]
+pub struct FillResponse {
+    /// ID of the filled deposit
+    pub id: ID,
+    pub maker: Addr,
+    pub taker: Addr,
+    /// Tokens sent to the maker
+    pub paid: Asset,
+    /// Tokens sent to the taker, after the service fee
+    pub received: Asset,
+    pub fee: Asset,
 }
 
 #[cw_serde]
 pub struct DepositsBySenderResponse {
     pub deposits: Vec<(ID, Deposit)>,
+    /// Cached metadata of cw20 tokens deposited or asked for, by token address
+    pub token_metadata: Vec<(Addr, TokenMetadata)>,
 }
 
 #[cw_serde]
 pub struct DepositByIdResponse {
     pub sender: Addr,
     pub deposit: Deposit,
+    /// Cached metadata of cw20 tokens deposited or asked for, by token address
+    pub token_metadata: Vec<(Addr, TokenMetadata)>,
+}
+
+#[cw_serde]
+pub struct FeeTierResponse {
+    pub volume: Uint128,
+    /// Index into configured tiers; `None` means the base service fee applies
+    pub tier: Option<u32>,
+    pub service_fee: Decimal,
+}
+
+#[cw_serde]
+pub struct ReferralRewardsResponse {
+    pub rewards: Vec<Asset>,
+}
+
+#[cw_serde]
+pub struct AccruedFeesResponse {
+    pub fees: Vec<Asset>,
+}
+
+/// Subset of cw20-stake queries used to look up taker's stake
+#[cw_serde]
+pub enum StakeQueryMsg {
+    StakedBalanceAtHeight {
+        address: String,
+        height: Option<u64>,
+    },
+}
+
+#[cw_serde]
+pub struct StakedBalanceAtHeightResponse {
+    pub balance: Uint128,
+    pub height: u64,
+}
+
+#[cw_serde]
+pub struct AssetRegistryResponse {
+    pub allowlist_only: bool,
+    pub assets: Vec<AssetRegistryEntry>,
+}
+
+#[cw_serde]
+pub struct AntiSpamConfigResponse {
+    pub max_offers_per_address: Option<u32>,
+    pub offer_bond: Option<Coin>,
+    pub keeper_bounty: Decimal,
+    pub min_deposits: Vec<Asset>,
+}
+
+#[cw_serde]
+pub enum TradeHistoryFilter {
+    Maker { address: String },
+    Taker { address: String },
+    Pair(Pair),
+}
+
+#[cw_serde]
+pub struct TradeHistoryResponse {
+    pub fills: Vec<Fill>,
+}
+
+#[cw_serde]
+pub struct VwapResponse {
+    /// Exchange asset paid per unit of deposited asset, averaged over time and volume
+    pub price: Decimal256,
+    /// Beginning of the averaged period, later than requested if pair wasn't traded before
+    pub start: Timestamp,
+    pub end: Timestamp,
+}
+
+#[cw_serde]
+pub struct SimulateAcceptResponse {
+    /// Reason the acceptance would fail; other fields are empty in that case
+    pub error: Option<String>,
+    /// Exchange asset and returned offer bond, if any
+    pub maker_receives: Vec<Asset>,
+    /// Deposit after the service fee and refund of funds above the expected amount, if any
+    pub taker_receives: Vec<Asset>,
+    /// Service fee charged, including referral rewards
+    pub fee: Option<Asset>,
+}
+
+#[cw_serde]
+pub struct SolvencyResponse {
+    pub solvent: bool,
+    pub assets: Vec<AssetSolvency>,
+}
+
+#[cw_serde]
+pub struct AssetSolvency {
+    pub asset: AssetType,
+    /// Escrowed deposits and bonds, prepaid bonds, referral rewards and accrued fees
+    pub liabilities: Uint128,
+    pub balance: Uint128,
+    /// Part of liabilities not covered by the balance
+    pub shortfall: Uint128,
 }
@@ -0,0 +1,1078 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{
+    coin, coins, to_binary, Addr, Binary, Decimal, Deps, DepsMut, Empty, Env, MessageInfo,
+    Response, StdResult, Uint128,
+};
+use cw_multi_test::{App, Contract, ContractWrapper, Executor};
+use cw_storage_plus::Map;
+
+use crate::error::ContractError;
+use crate::events::OtcEvent;
+use crate::msg::{
+    AccruedFeesResponse, ExecuteMsg, FeeShare, FeeTierResponse, FillResponse, InstantiateMsg,
+    QueryMsg, StakeQueryMsg, StakedBalanceAtHeightResponse, StakingConfigMsg, SudoMsg,
+};
+use crate::state::{
+    sealed_bid_commitment, Asset, AssetType, EnglishAuction, SealedBidAuction, StakingDiscount,
+};
+
+fn contract_otc() -> Box<dyn Contract<Empty>> {
+    Box::new(
+        ContractWrapper::new(
+            crate::contract::execute,
+            crate::contract::instantiate,
+            crate::contract::query,
+        )
+        .with_sudo(crate::contract::sudo)
+        .with_reply(crate::contract::reply),
+    )
+}
+
+/// Minimal stand-in for a cw20-stake contract with stakes fixed at instantiation
+mod mock_stake {
+    use super::*;
+
+    #[cw_serde]
+    pub struct InstantiateMsg {
+        pub stakes: Vec<(String, Uint128)>,
+    }
+
+    const STAKES: Map<&Addr, Uint128> = Map::new("stakes");
+
+    pub fn instantiate(
+        deps: DepsMut,
+        _env: Env,
+        _info: MessageInfo,
+        msg: InstantiateMsg,
+    ) -> StdResult<Response> {
+        for (address, stake) in msg.stakes {
+            STAKES.save(deps.storage, &Addr::unchecked(address), &stake)?;
+        }
+        Ok(Response::new())
+    }
+
+    pub fn execute(
+        _deps: DepsMut,
+        _env: Env,
+        _info: MessageInfo,
+        _msg: Empty,
+    ) -> StdResult<Response> {
+        Ok(Response::new())
+    }
+
+    pub fn query(deps: Deps, env: Env, msg: StakeQueryMsg) -> StdResult<Binary> {
+        match msg {
+            StakeQueryMsg::StakedBalanceAtHeight { address, .. } => {
+                to_binary(&StakedBalanceAtHeightResponse {
+                    balance: STAKES
+                        .may_load(deps.storage, &Addr::unchecked(address))?
+                        .unwrap_or_default(),
+                    height: env.block.height,
+                })
+            }
+        }
+    }
+
+    pub fn contract() -> Box<dyn Contract<Empty>> {
+        Box::new(ContractWrapper::new(execute, instantiate, query))
+    }
+}
+
+#[test]
+fn staked_balance_discounts_service_fee() {
+    let owner = Addr::unchecked("owner");
+    let maker = Addr::unchecked("maker");
+    let staker = Addr::unchecked("staker");
+    let small_staker = Addr::unchecked("small_staker");
+    let taker = Addr::unchecked("taker");
+
+    let mut app = App::new(|router, _, storage| {
+        router
+            .bank
+            .init_balance(storage, &maker, coins(200_999, "ujuno"))
+            .unwrap();
+        for user in [&staker, &small_staker, &taker] {
+            router
+                .bank
+                .init_balance(storage, user, coins(1_000, "uusdc"))
+                .unwrap();
+        }
+    });
+
+    let stake_code_id = app.store_code(mock_stake::contract());
+    let stake_contract = app
+        .instantiate_contract(
+            stake_code_id,
+            owner.clone(),
+            &mock_stake::InstantiateMsg {
+                stakes: vec![
+                    (staker.to_string(), Uint128::new(5_000)),
+                    (small_staker.to_string(), Uint128::new(1_000)),
+                ],
+            },
+            &[],
+            "stake",
+            None,
+        )
+        .unwrap();
+
+    let otc_code_id = app.store_code(contract_otc());
+    let otc_contract = app
+        .instantiate_contract(
+            otc_code_id,
+            owner,
+            &InstantiateMsg {
+                admin: None,
+                fee_recipients: vec![FeeShare {
+                    address: "fees".to_owned(),
+                    weight: 10_000,
+                }],
+                volume_config: None,
+                referral_share: None,
+                staking_config: Some(StakingConfigMsg {
+                    staking_contract: stake_contract.to_string(),
+                    discounts: vec![
+                        StakingDiscount {
+                            min_stake: Uint128::new(1_000),
+                            discount: Decimal::percent(25),
+                        },
+                        StakingDiscount {
+                            min_stake: Uint128::new(5_000),
+                            discount: Decimal::percent(50),
+                        },
+                    ],
+                }),
+            },
+            &[],
+            "otc",
+            None,
+        )
+        .unwrap();
+
+    for amount in [100_000, 100_000, 999] {
+        app.execute_contract(
+            maker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::Deposit {
+                exchange: Asset::new_native(1_000, "uusdc"),
+                from: None,
+                referrer: None,
+                expires: None,
+            },
+            &coins(amount, "ujuno"),
+        )
+        .unwrap();
+    }
+
+    let accrued_fees = |app: &App| -> Vec<Asset> {
+        app.wrap()
+            .query_wasm_smart::<AccruedFeesResponse>(&otc_contract, &QueryMsg::AccruedFees {})
+            .unwrap()
+            .fees
+    };
+
+    // Taker without stake pays full 1% fee
+    app.execute_contract(
+        taker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::AcceptExchange {
+            deposit_id: 0,
+            referrer: None,
+        },
+        &coins(1_000, "uusdc"),
+    )
+    .unwrap();
+    assert_eq!(accrued_fees(&app), vec![Asset::new_native(1_000, "ujuno")]);
+    assert_eq!(
+        app.wrap().query_balance(&taker, "ujuno").unwrap().amount,
+        Uint128::new(99_000)
+    );
+
+    // Staker pays half of the fee
+    app.execute_contract(
+        staker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::AcceptExchange {
+            deposit_id: 1,
+            referrer: None,
+        },
+        &coins(1_000, "uusdc"),
+    )
+    .unwrap();
+    assert_eq!(accrued_fees(&app), vec![Asset::new_native(1_500, "ujuno")]);
+    assert_eq!(
+        app.wrap().query_balance(&staker, "ujuno").unwrap().amount,
+        Uint128::new(99_500)
+    );
+
+    // Discounted fee of 0.75% is rounded once, 999 * 0.0075 = 7.49
+    app.execute_contract(
+        small_staker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::AcceptExchange {
+            deposit_id: 2,
+            referrer: None,
+        },
+        &coins(1_000, "uusdc"),
+    )
+    .unwrap();
+    assert_eq!(accrued_fees(&app), vec![Asset::new_native(1_507, "ujuno")]);
+    assert_eq!(
+        app.wrap().query_balance(&maker, "uusdc").unwrap().amount,
+        Uint128::new(3_000)
+    );
+}
+
+#[test]
+fn chain_governance_and_clock_through_sudo() {
+    let owner = Addr::unchecked("owner");
+    let maker = Addr::unchecked("maker");
+    let taker = Addr::unchecked("taker");
+
+    let mut app = App::new(|router, _, storage| {
+        router
+            .bank
+            .init_balance(
+                storage,
+                &maker,
+                vec![coin(3_000, "uatom"), coin(300, "ujuno")],
+            )
+            .unwrap();
+    });
+
+    let otc_code_id = app.store_code(contract_otc());
+    let otc_contract = app
+        .instantiate_contract(
+            otc_code_id,
+            owner.clone(),
+            &InstantiateMsg {
+                admin: None,
+                fee_recipients: vec![FeeShare {
+                    address: "fees".to_owned(),
+                    weight: 10_000,
+                }],
+                volume_config: None,
+                referral_share: None,
+                staking_config: None,
+            },
+            &[],
+            "otc",
+            None,
+        )
+        .unwrap();
+    app.execute_contract(
+        owner,
+        otc_contract.clone(),
+        &ExecuteMsg::UpdateAntiSpamConfig {
+            max_offers_per_address: None,
+            offer_bond: Some(coin(100, "ujuno")),
+            keeper_bounty: Some(Decimal::percent(10)),
+            min_deposits: vec![],
+        },
+        &[],
+    )
+    .unwrap();
+
+    // Governance raises the service fee
+    let err = app
+        .wasm_sudo(
+            otc_contract.clone(),
+            &SudoMsg::UpdateFeeConfig {
+                fee_recipients: None,
+                service_fee: Some(Decimal::one()),
+                referral_share: None,
+                volume_config: None,
+            },
+        )
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast().unwrap(),
+        ContractError::InvalidServiceFee {}
+    ));
+    app.wasm_sudo(
+        otc_contract.clone(),
+        &SudoMsg::UpdateFeeConfig {
+            fee_recipients: None,
+            service_fee: Some(Decimal::percent(2)),
+            referral_share: None,
+            volume_config: None,
+        },
+    )
+    .unwrap();
+    let fee_tier: FeeTierResponse = app
+        .wrap()
+        .query_wasm_smart(
+            &otc_contract,
+            &QueryMsg::FeeTier {
+                address: taker.to_string(),
+            },
+        )
+        .unwrap();
+    assert_eq!(fee_tier.service_fee, Decimal::percent(2));
+
+    let expires = app.block_info().time.plus_seconds(100);
+    for expires in [None, Some(expires), Some(expires)] {
+        app.execute_contract(
+            maker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::Deposit {
+                exchange: Asset::new_native(1_000, "uusdc"),
+                from: None,
+                referrer: None,
+                expires,
+            },
+            &[coin(1_000, "uatom"), coin(100, "ujuno")],
+        )
+        .unwrap();
+    }
+    let balance = |app: &App, denom: &str| -> u128 {
+        app.wrap()
+            .query_balance(&maker, denom)
+            .unwrap()
+            .amount
+            .u128()
+    };
+
+    // Governance pauses withdrawals, but can still refund a stuck deposit
+    app.wasm_sudo(
+        otc_contract.clone(),
+        &SudoMsg::SetPause {
+            deposits: None,
+            acceptances: None,
+            withdrawals: Some(true),
+        },
+    )
+    .unwrap();
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::Withdraw { id: Some(0) },
+        &[],
+    )
+    .unwrap_err();
+    app.wasm_sudo(
+        otc_contract.clone(),
+        &SudoMsg::ForceRefund {
+            address: maker.to_string(),
+            id: Some(0),
+        },
+    )
+    .unwrap();
+    assert_eq!(balance(&app, "uatom"), 1_000);
+    assert_eq!(balance(&app, "ujuno"), 100);
+
+    // Clock skips maintenance while withdrawals are paused
+    app.update_block(|block| block.time = block.time.plus_seconds(200));
+    app.wasm_sudo(otc_contract.clone(), &SudoMsg::ExpireOffers { limit: None })
+        .unwrap();
+    assert_eq!(balance(&app, "uatom"), 1_000);
+
+    app.wasm_sudo(
+        otc_contract.clone(),
+        &SudoMsg::SetPause {
+            deposits: None,
+            acceptances: None,
+            withdrawals: Some(false),
+        },
+    )
+    .unwrap();
+    app.wasm_sudo(
+        otc_contract.clone(),
+        &SudoMsg::ExpireOffers { limit: Some(1) },
+    )
+    .unwrap();
+    assert_eq!(balance(&app, "uatom"), 2_000);
+    assert_eq!(balance(&app, "ujuno"), 200);
+    app.wasm_sudo(otc_contract, &SudoMsg::ExpireOffers { limit: None })
+        .unwrap();
+    // Without a keeper whole bond goes back to the depositor
+    assert_eq!(balance(&app, "uatom"), 3_000);
+    assert_eq!(balance(&app, "ujuno"), 300);
+}
+
+#[test]
+fn typed_events_for_indexers() {
+    let maker = Addr::unchecked("maker");
+    let taker = Addr::unchecked("taker");
+
+    let mut app = App::new(|router, _, storage| {
+        router
+            .bank
+            .init_balance(storage, &maker, coins(3_000, "uatom"))
+            .unwrap();
+        router
+            .bank
+            .init_balance(storage, &taker, coins(500, "uusdc"))
+            .unwrap();
+    });
+
+    let otc_code_id = app.store_code(contract_otc());
+    let otc_contract = app
+        .instantiate_contract(
+            otc_code_id,
+            maker.clone(),
+            &InstantiateMsg {
+                admin: None,
+                fee_recipients: vec![FeeShare {
+                    address: "fees".to_owned(),
+                    weight: 10_000,
+                }],
+                volume_config: None,
+                referral_share: None,
+                staking_config: None,
+            },
+            &[],
+            "otc",
+            None,
+        )
+        .unwrap();
+
+    let mut events = vec![];
+    let expiry = app.block_info().time.plus_seconds(100);
+    for expires in [None, None, Some(expiry)] {
+        let res = app
+            .execute_contract(
+                maker.clone(),
+                otc_contract.clone(),
+                &ExecuteMsg::Deposit {
+                    exchange: Asset::new_native(500, "uusdc"),
+                    from: None,
+                    referrer: None,
+                    expires,
+                },
+                &coins(1_000, "uatom"),
+            )
+            .unwrap();
+        events.extend(OtcEvent::from_events(&res.events).unwrap());
+    }
+    let res = app
+        .execute_contract(
+            taker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+            &coins(500, "uusdc"),
+        )
+        .unwrap();
+    events.extend(OtcEvent::from_events(&res.events).unwrap());
+
+    // Offers removed by sweeping are reported as withdrawn too
+    app.update_block(|block| block.time = expiry);
+    let res = app
+        .execute_contract(
+            taker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::SweepExpired { limit: None },
+            &[],
+        )
+        .unwrap();
+    let withdraw = res
+        .events
+        .iter()
+        .find(|event| event.ty == "wasm-otc_withdraw")
+        .unwrap();
+    for (key, value) in [("deposit_denom", "uatom"), ("deposit_kind", "native")] {
+        assert!(withdraw
+            .attributes
+            .iter()
+            .any(|attribute| attribute.key == key && attribute.value == value));
+    }
+    events.extend(OtcEvent::from_events(&res.events).unwrap());
+    let res = app
+        .execute_contract(
+            maker.clone(),
+            otc_contract,
+            &ExecuteMsg::Withdraw { id: None },
+            &[],
+        )
+        .unwrap();
+    events.extend(OtcEvent::from_events(&res.events).unwrap());
+
+    let deposit = |id| OtcEvent::Deposit {
+        id,
+        maker: maker.clone(),
+        deposit: Asset::new_native(1_000, "uatom"),
+        exchange: Asset::new_native(500, "uusdc"),
+    };
+    assert_eq!(
+        events,
+        vec![
+            deposit(0),
+            deposit(1),
+            deposit(2),
+            OtcEvent::Fill(FillResponse {
+                id: 0,
+                maker: maker.clone(),
+                taker,
+                paid: Asset::new_native(500, "uusdc"),
+                received: Asset::new_native(990, "uatom"),
+                fee: Asset::new_native(10, "uatom"),
+            }),
+            OtcEvent::Withdraw {
+                id: 2,
+                maker: maker.clone(),
+                deposit: Asset::new_native(1_000, "uatom"),
+            },
+            OtcEvent::Withdraw {
+                id: 1,
+                maker: maker.clone(),
+                deposit: Asset::new_native(1_000, "uatom"),
+            },
+        ]
+    );
+}
+
+#[test]
+fn english_auction() {
+    let maker = Addr::unchecked("maker");
+    let alice = Addr::unchecked("alice");
+    let bob = Addr::unchecked("bob");
+
+    let mut app = App::new(|router, _, storage| {
+        router
+            .bank
+            .init_balance(storage, &maker, coins(2_000, "uatom"))
+            .unwrap();
+        for bidder in [&alice, &bob] {
+            router
+                .bank
+                .init_balance(storage, bidder, coins(200, "uusdc"))
+                .unwrap();
+        }
+    });
+
+    let otc_code_id = app.store_code(contract_otc());
+    let otc_contract = app
+        .instantiate_contract(
+            otc_code_id,
+            maker.clone(),
+            &InstantiateMsg {
+                admin: None,
+                fee_recipients: vec![FeeShare {
+                    address: "fees".to_owned(),
+                    weight: 10_000,
+                }],
+                volume_config: None,
+                referral_share: None,
+                staking_config: None,
+            },
+            &[],
+            "otc",
+            None,
+        )
+        .unwrap();
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::Deposit {
+            exchange: Asset::new_native(100, "uusdc"),
+            from: None,
+            referrer: None,
+            expires: None,
+        },
+        &coins(1_000, "uatom"),
+    )
+    .unwrap();
+    let start = app.block_info().time;
+    let open = |app: &mut App, min_increment, extension| {
+        app.execute_contract(
+            maker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::OpenAuction {
+                deposit_id: 0,
+                min_increment: Uint128::new(min_increment),
+                end: start.plus_seconds(100),
+                extension,
+            },
+            &[],
+        )
+        .map_err(|err| err.downcast::<ContractError>().unwrap())
+    };
+    let err = open(&mut app, 0, 30).unwrap_err();
+    assert!(matches!(err, ContractError::InvalidAuction {}));
+    let err = open(&mut app, 10, u64::MAX).unwrap_err();
+    assert!(matches!(err, ContractError::InvalidAuction {}));
+    open(&mut app, 10, 30).unwrap();
+    let update_registry = |app: &mut App, block: Vec<AssetType>, remove: Vec<AssetType>| {
+        app.execute_contract(
+            maker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::UpdateAssetRegistry {
+                allow: vec![],
+                block,
+                remove,
+                allowlist_only: None,
+            },
+            &[],
+        )
+        .unwrap();
+    };
+
+    let bid = |app: &mut App, bidder: &Addr, amount| {
+        app.execute_contract(
+            bidder.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::Bid { deposit_id: 0 },
+            &coins(amount, "uusdc"),
+        )
+        .map_err(|err| err.downcast::<ContractError>().unwrap())
+    };
+    let balance = |app: &App, address: &Addr, denom: &str| -> u128 {
+        app.wrap()
+            .query_balance(address, denom)
+            .unwrap()
+            .amount
+            .u128()
+    };
+
+    // Auctioned offer can't be accepted directly
+    let err = app
+        .execute_contract(
+            alice.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::AcceptExchange {
+                deposit_id: 0,
+                referrer: None,
+            },
+            &coins(100, "uusdc"),
+        )
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast().unwrap(),
+        ContractError::AuctionInProgress { id: 0 }
+    ));
+
+    let err = bid(&mut app, &alice, 90).unwrap_err();
+    assert!(matches!(err, ContractError::BidTooLow { .. }));
+    bid(&mut app, &alice, 100).unwrap();
+    let err = bid(&mut app, &bob, 105).unwrap_err();
+    assert_eq!(err.to_string(), "Bid has to be at least 110uusdc");
+    bid(&mut app, &bob, 120).unwrap();
+    assert_eq!(balance(&app, &alice, "uusdc"), 200);
+
+    let err = app
+        .execute_contract(
+            maker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::Withdraw { id: None },
+            &[],
+        )
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast().unwrap(),
+        ContractError::AuctionInProgress { id: 0 }
+    ));
+
+    // Bid close to the end extends the auction
+    app.update_block(|block| block.time = start.plus_seconds(80));
+    bid(&mut app, &alice, 130).unwrap();
+    assert_eq!(balance(&app, &bob, "uusdc"), 200);
+    let auction: EnglishAuction = app
+        .wrap()
+        .query_wasm_smart(&otc_contract, &QueryMsg::Auction { deposit_id: 0 })
+        .unwrap();
+    assert_eq!(auction.end, start.plus_seconds(110));
+
+    app.update_block(|block| block.time = start.plus_seconds(105));
+    let err = app
+        .execute_contract(
+            bob.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::SettleAuction { deposit_id: 0 },
+            &[],
+        )
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast().unwrap(),
+        ContractError::AuctionNotEnded { id: 0 }
+    ));
+
+    app.update_block(|block| block.time = start.plus_seconds(110));
+    let err = bid(&mut app, &bob, 200).unwrap_err();
+    assert!(matches!(err, ContractError::AuctionEnded { id: 0 }));
+    app.execute_contract(
+        bob.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::SettleAuction { deposit_id: 0 },
+        &[],
+    )
+    .unwrap();
+    assert_eq!(balance(&app, &maker, "uusdc"), 130);
+    assert_eq!(balance(&app, &alice, "uatom"), 990);
+    assert_eq!(balance(&app, &alice, "uusdc"), 70);
+    assert_eq!(balance(&app, &bob, "uusdc"), 200);
+    assert_eq!(balance(&app, &otc_contract, "uusdc"), 0);
+    assert_eq!(balance(&app, &otc_contract, "uatom"), 10);
+
+    // Auction that can't settle because of a blocked asset refunds the best bid and leaves
+    // the offer withdrawable
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::Deposit {
+            exchange: Asset::new_native(100, "uusdc"),
+            from: None,
+            referrer: None,
+            expires: None,
+        },
+        &coins(1_000, "uatom"),
+    )
+    .unwrap();
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::OpenAuction {
+            deposit_id: 1,
+            min_increment: Uint128::new(10),
+            end: start.plus_seconds(200),
+            extension: 30,
+        },
+        &[],
+    )
+    .unwrap();
+    app.execute_contract(
+        bob.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::Bid { deposit_id: 1 },
+        &coins(150, "uusdc"),
+    )
+    .unwrap();
+    app.update_block(|block| block.time = start.plus_seconds(200));
+    update_registry(
+        &mut app,
+        vec![AssetType::Native("uatom".to_owned())],
+        vec![],
+    );
+    app.execute_contract(
+        bob.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::SettleAuction { deposit_id: 1 },
+        &[],
+    )
+    .unwrap();
+    assert_eq!(balance(&app, &bob, "uusdc"), 200);
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::Withdraw { id: Some(1) },
+        &[],
+    )
+    .unwrap();
+    assert_eq!(balance(&app, &maker, "uatom"), 1_000);
+    assert_eq!(balance(&app, &otc_contract, "uatom"), 10);
+}
+
+#[test]
+fn sealed_bid_auction() {
+    let maker = Addr::unchecked("maker");
+    let alice = Addr::unchecked("alice");
+    let bob = Addr::unchecked("bob");
+    let carol = Addr::unchecked("carol");
+    let dave = Addr::unchecked("dave");
+
+    let mut app = App::new(|router, _, storage| {
+        router
+            .bank
+            .init_balance(storage, &maker, coins(2_000, "uatom"))
+            .unwrap();
+        for bidder in [&alice, &bob, &carol, &dave] {
+            router
+                .bank
+                .init_balance(storage, bidder, coins(300, "uusdc"))
+                .unwrap();
+        }
+    });
+
+    let otc_code_id = app.store_code(contract_otc());
+    let otc_contract = app
+        .instantiate_contract(
+            otc_code_id,
+            maker.clone(),
+            &InstantiateMsg {
+                admin: None,
+                fee_recipients: vec![FeeShare {
+                    address: "fees".to_owned(),
+                    weight: 10_000,
+                }],
+                volume_config: None,
+                referral_share: None,
+                staking_config: None,
+            },
+            &[],
+            "otc",
+            None,
+        )
+        .unwrap();
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::Deposit {
+            exchange: Asset::new_native(100, "uusdc"),
+            from: None,
+            referrer: None,
+            expires: None,
+        },
+        &coins(1_000, "uatom"),
+    )
+    .unwrap();
+    let start = app.block_info().time;
+    let err = app
+        .execute_contract(
+            maker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::OpenSealedBidAuction {
+                deposit_id: 0,
+                commit_end: start.plus_seconds(100),
+                reveal_end: start.plus_seconds(100),
+                penalty: Decimal::percent(10),
+            },
+            &[],
+        )
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast().unwrap(),
+        ContractError::InvalidAuction {}
+    ));
+    // Bids never revealed have to cost something
+    let err = app
+        .execute_contract(
+            maker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::OpenSealedBidAuction {
+                deposit_id: 0,
+                commit_end: start.plus_seconds(100),
+                reveal_end: start.plus_seconds(200),
+                penalty: Decimal::zero(),
+            },
+            &[],
+        )
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast().unwrap(),
+        ContractError::InvalidPenalty {}
+    ));
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::OpenSealedBidAuction {
+            deposit_id: 0,
+            commit_end: start.plus_seconds(100),
+            reveal_end: start.plus_seconds(200),
+            penalty: Decimal::percent(10),
+        },
+        &[],
+    )
+    .unwrap();
+
+    let commit = |app: &mut App, bidder: &Addr, amount: u128, escrow| {
+        app.execute_contract(
+            bidder.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::CommitBid {
+                deposit_id: 0,
+                commitment: sealed_bid_commitment(0, bidder, Uint128::new(amount), "salt"),
+            },
+            &coins(escrow, "uusdc"),
+        )
+        .map_err(|err| err.downcast::<ContractError>().unwrap())
+    };
+    let reveal = |app: &mut App, bidder: &Addr, amount, salt: &str| {
+        app.execute_contract(
+            bidder.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::RevealBid {
+                deposit_id: 0,
+                amount: Uint128::new(amount),
+                salt: salt.to_owned(),
+            },
+            &[],
+        )
+        .map_err(|err| err.downcast::<ContractError>().unwrap())
+    };
+    let settle = |app: &mut App| {
+        app.execute_contract(
+            maker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::SettleAuction { deposit_id: 0 },
+            &[],
+        )
+        .map_err(|err| err.downcast::<ContractError>().unwrap())
+    };
+    let balance = |app: &App, address: &Addr, denom: &str| -> u128 {
+        app.wrap()
+            .query_balance(address, denom)
+            .unwrap()
+            .amount
+            .u128()
+    };
+
+    // Escrow has to cover at least the reserve
+    let err = commit(&mut app, &alice, 90, 90).unwrap_err();
+    assert!(matches!(err, ContractError::BidTooLow { .. }));
+    commit(&mut app, &alice, 150, 200).unwrap();
+    let err = commit(&mut app, &alice, 100, 100).unwrap_err();
+    assert!(matches!(err, ContractError::BidAlreadyCommitted {}));
+    commit(&mut app, &bob, 130, 150).unwrap();
+    commit(&mut app, &carol, 120, 100).unwrap();
+    commit(&mut app, &dave, 50, 100).unwrap();
+
+    let err = reveal(&mut app, &bob, 130, "salt").unwrap_err();
+    assert!(matches!(err, ContractError::AuctionNotEnded { id: 0 }));
+
+    app.update_block(|block| block.time = start.plus_seconds(100));
+    let err = commit(&mut app, &carol, 120, 120).unwrap_err();
+    assert!(matches!(err, ContractError::AuctionEnded { id: 0 }));
+    let err = reveal(&mut app, &bob, 140, "salt").unwrap_err();
+    assert!(matches!(err, ContractError::CommitmentMismatch {}));
+    reveal(&mut app, &bob, 130, "salt").unwrap();
+    assert_eq!(balance(&app, &bob, "uusdc"), 150);
+    // Bid below the reserve forfeits the penalty like an unrevealed one
+    reveal(&mut app, &dave, 50, "salt").unwrap();
+    assert_eq!(balance(&app, &dave, "uusdc"), 290);
+    assert_eq!(balance(&app, &maker, "uusdc"), 10);
+    // Outbid bidder gets the whole escrow back
+    reveal(&mut app, &alice, 150, "salt").unwrap();
+    assert_eq!(balance(&app, &bob, "uusdc"), 300);
+    let auction: SealedBidAuction = app
+        .wrap()
+        .query_wasm_smart(&otc_contract, &QueryMsg::SealedBidAuction { deposit_id: 0 })
+        .unwrap();
+    assert_eq!(auction.best_bid.unwrap().amount, Uint128::new(150));
+
+    let err = app
+        .execute_contract(
+            maker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::Withdraw { id: None },
+            &[],
+        )
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast().unwrap(),
+        ContractError::AuctionInProgress { id: 0 }
+    ));
+    let err = settle(&mut app).unwrap_err();
+    assert!(matches!(err, ContractError::AuctionNotEnded { id: 0 }));
+
+    // Carol never reveals and forfeits 10% of her escrow to the maker
+    app.update_block(|block| block.time = start.plus_seconds(200));
+    let err = reveal(&mut app, &carol, 120, "salt").unwrap_err();
+    assert!(matches!(err, ContractError::AuctionEnded { id: 0 }));
+
+    let claim = |app: &mut App, bidder: &Addr, deposit_id| {
+        app.execute_contract(
+            bidder.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::ClaimBidEscrow { deposit_id },
+            &[],
+        )
+        .map_err(|err| err.downcast::<ContractError>().unwrap())
+    };
+    let err = claim(&mut app, &carol, 0).unwrap_err();
+    assert!(matches!(err, ContractError::AuctionNotEnded { id: 0 }));
+    settle(&mut app).unwrap();
+    assert_eq!(balance(&app, &maker, "uusdc"), 170);
+    assert_eq!(balance(&app, &alice, "uatom"), 990);
+    assert_eq!(balance(&app, &alice, "uusdc"), 150);
+    assert_eq!(balance(&app, &otc_contract, "uatom"), 10);
+
+    // Unrevealed escrow is claimed by its bidder, so settling doesn't loop over bids
+    assert_eq!(balance(&app, &carol, "uusdc"), 200);
+    claim(&mut app, &carol, 0).unwrap();
+    assert_eq!(balance(&app, &carol, "uusdc"), 290);
+    assert_eq!(balance(&app, &otc_contract, "uusdc"), 0);
+    claim(&mut app, &carol, 0).unwrap_err();
+
+    // Auction that can't settle because of a blocked asset refunds the best bid, while
+    // unrevealed bids are claimed back whole
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::Deposit {
+            exchange: Asset::new_native(100, "uusdc"),
+            from: None,
+            referrer: None,
+            expires: None,
+        },
+        &coins(1_000, "uatom"),
+    )
+    .unwrap();
+    let open = |app: &mut App| {
+        app.execute_contract(
+            maker.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::OpenSealedBidAuction {
+                deposit_id: 1,
+                commit_end: start.plus_seconds(300),
+                reveal_end: start.plus_seconds(400),
+                penalty: Decimal::percent(10),
+            },
+            &[],
+        )
+        .map_err(|err| err.downcast::<ContractError>().unwrap())
+    };
+    open(&mut app).unwrap();
+    for (bidder, amount) in [(&bob, 120), (&carol, 130)] {
+        app.execute_contract(
+            bidder.clone(),
+            otc_contract.clone(),
+            &ExecuteMsg::CommitBid {
+                deposit_id: 1,
+                commitment: sealed_bid_commitment(1, bidder, Uint128::new(amount), "salt"),
+            },
+            &coins(150, "uusdc"),
+        )
+        .unwrap();
+    }
+    app.update_block(|block| block.time = start.plus_seconds(300));
+    app.execute_contract(
+        bob.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::RevealBid {
+            deposit_id: 1,
+            amount: Uint128::new(120),
+            salt: "salt".to_owned(),
+        },
+        &[],
+    )
+    .unwrap();
+    app.update_block(|block| block.time = start.plus_seconds(400));
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::UpdateAssetRegistry {
+            allow: vec![],
+            block: vec![AssetType::Native("uatom".to_owned())],
+            remove: vec![],
+            allowlist_only: None,
+        },
+        &[],
+    )
+    .unwrap();
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::SettleAuction { deposit_id: 1 },
+        &[],
+    )
+    .unwrap();
+    assert_eq!(balance(&app, &bob, "uusdc"), 300);
+
+    // Deposit can't be auctioned again until the earlier escrows are claimed
+    let err = open(&mut app).unwrap_err();
+    assert!(matches!(err, ContractError::UnclaimedEscrows { id: 1 }));
+    claim(&mut app, &carol, 1).unwrap();
+    assert_eq!(balance(&app, &carol, "uusdc"), 290);
+    app.execute_contract(
+        maker.clone(),
+        otc_contract.clone(),
+        &ExecuteMsg::Withdraw { id: Some(1) },
+        &[],
+    )
+    .unwrap();
+    assert_eq!(balance(&app, &maker, "uatom"), 1_000);
+    assert_eq!(balance(&app, &otc_contract, "uusdc"), 0);
+    assert_eq!(balance(&app, &otc_contract, "uatom"), 10);
+}
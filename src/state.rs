@@ -1,7 +1,11 @@
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, Decimal, Order, StdResult, Storage, Uint128};
-use cw_storage_plus::{Item, Map};
+use cosmwasm_std::{
+    Addr, Binary, Coin, Decimal, Decimal256, Order, StdResult, Storage, Timestamp, Uint128, Uint256,
+};
+use cw_storage_plus::{Bound, Index, IndexList, IndexedMap, Item, Map, MultiIndex};
+use sha2::{Digest, Sha256};
 
+use std::collections::BTreeMap;
 use std::fmt;
 
 #[cw_serde]
@@ -23,6 +27,16 @@ impl fmt::Display for AssetType {
     }
 }
 
+impl AssetType {
+    /// Storage key that keeps native denoms and cw20 addresses apart
+    pub fn key(&self) -> String {
+        match self {
+            AssetType::Native(denom) => format!("native:{}", denom),
+            AssetType::Cw20(address) => format!("cw20:{}", address),
+        }
+    }
+}
+
 #[cw_serde]
 pub struct Asset {
     pub denom: AssetType,
@@ -55,16 +69,194 @@ impl fmt::Display for Asset {
 pub struct Deposit {
     pub deposit: Asset,
     pub offer: Offer,
+    /// Address that referred the depositor; credited with part of the fee on fill
+    pub referrer: Option<Addr>,
+    /// Anti-spam bond returned to the depositor on withdraw or fill
+    pub bond: Option<Asset>,
 }
 
 #[cw_serde]
 pub struct Offer {
+    /// For priced offers, the amount required for the whole deposit
     pub exchange: Asset,
     pub from: Option<Addr>,
+    /// Offer can't be accepted after this time and may be swept back to the depositor
+    pub expires: Option<Timestamp>,
+    /// Units of exchange asset per unit of deposit; if set, required amount is computed
+    /// from it at fill time
+    pub price: Option<Decimal>,
+    /// Price decaying over time; takes precedence over `price`
+    pub dutch_auction: Option<DutchAuction>,
+}
+
+impl Offer {
+    /// Amount of exchange asset required for given quantity of the deposit at given time;
+    /// priced offers round up, in favour of the depositor
+    pub fn required_amount(&self, quantity: Uint128, now: Timestamp) -> StdResult<Uint128> {
+        match (&self.dutch_auction, self.price) {
+            (Some(auction), _) => amount_at_price(quantity, auction.price_at(now)),
+            (None, Some(price)) => amount_at_price(quantity, price),
+            (None, None) => Ok(self.exchange.amount),
+        }
+    }
+}
+
+/// Price going down from `start_price` to `floor_price` between `start` and `end`
+#[cw_serde]
+pub struct DutchAuction {
+    pub start_price: Decimal,
+    pub floor_price: Decimal,
+    pub start: Timestamp,
+    pub end: Timestamp,
+    /// Number of equal price drops; price decays linearly if not set
+    pub steps: Option<u32>,
+}
+
+impl DutchAuction {
+    pub fn is_valid(&self) -> bool {
+        !self.floor_price.is_zero()
+            && self.floor_price <= self.start_price
+            && self.start < self.end
+            && match self.steps {
+                // Steps shorter than a second would never be observed
+                Some(steps) => {
+                    steps > 0 && u64::from(steps) <= self.end.seconds() - self.start.seconds()
+                }
+                None => true,
+            }
+    }
+
+    pub fn price_at(&self, time: Timestamp) -> Decimal {
+        let duration = self.end.seconds() - self.start.seconds();
+        let elapsed = time
+            .seconds()
+            .saturating_sub(self.start.seconds())
+            .min(duration);
+        let decayed = match self.steps {
+            Some(steps) => {
+                // Widened so that long auctions with many steps can't overflow
+                let steps = u128::from(steps);
+                let drops = u128::from(elapsed) * steps / u128::from(duration);
+                Decimal::from_ratio(drops, steps)
+            }
+            None => Decimal::from_ratio(elapsed, duration),
+        };
+        self.start_price - (self.start_price - self.floor_price) * decayed
+    }
+}
+
+/// Quantity multiplied by price, rounded up
+pub fn amount_at_price(quantity: Uint128, price: Decimal) -> StdResult<Uint128> {
+    let numerator = Uint256::from(quantity) * Uint256::from(price.atomics());
+    let denominator = Uint256::from(Decimal::one().atomics());
+    let amount = (numerator + denominator - Uint256::one()) / denominator;
+    Ok(amount.try_into()?)
+}
+
+pub const ADMIN: Item<Addr> = Item::new("admin");
+
+#[cw_serde]
+#[derive(Default)]
+pub struct PauseState {
+    pub deposits: bool,
+    pub acceptances: bool,
+    pub withdrawals: bool,
 }
 
+pub const PAUSE_STATE: Item<PauseState> = Item::new("pause_state");
+
+#[cw_serde]
+pub enum AssetListing {
+    Allowed,
+    Blocked,
+}
+
+#[cw_serde]
+pub struct AssetRegistryEntry {
+    pub asset: AssetType,
+    pub listing: AssetListing,
+}
+
+/// Admin managed allowlist and blocklist, keyed by `AssetType::key`
+pub const ASSET_REGISTRY: Map<&str, AssetRegistryEntry> = Map::new("asset_registry");
+
+/// If set, only explicitly allowed assets can be traded
+pub const ALLOWLIST_ONLY: Item<bool> = Item::new("allowlist_only");
+
+pub fn is_asset_permitted(storage: &dyn Storage, asset: &AssetType) -> StdResult<bool> {
+    Ok(match ASSET_REGISTRY.may_load(storage, &asset.key())? {
+        Some(entry) => entry.listing == AssetListing::Allowed,
+        None => !ALLOWLIST_ONLY.may_load(storage)?.unwrap_or_default(),
+    })
+}
+
+pub fn get_asset_registry(
+    storage: &dyn Storage,
+    start_after: Option<AssetType>,
+    limit: usize,
+) -> StdResult<Vec<AssetRegistryEntry>> {
+    let start_after = start_after.map(|asset| asset.key());
+    ASSET_REGISTRY
+        .range(
+            storage,
+            start_after.as_deref().map(Bound::exclusive),
+            None,
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| Ok(item?.1))
+        .collect()
+}
+
+#[cw_serde]
+pub struct TokenMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+/// Metadata of cw20 tokens seen in deposits, cached at deposit time
+pub const TOKEN_METADATA: Map<&Addr, TokenMetadata> = Map::new("token_metadata");
+
+/// Cw20 tokens whose received amounts are measured as a change of contract's balance,
+/// e.g. tokens taking a transfer tax, mapped to the balance the contract is known to hold
+pub const CW20_RESERVES: Map<&Addr, Uint128> = Map::new("cw20_reserves");
+/// Tokens whose reserve is synced to the contract's balance by the reply with given ID
+pub const PENDING_RESERVE_SYNCS: Map<u64, Addr> = Map::new("pending_reserve_syncs");
+/// Last reply ID handed out to a reserve sync
+pub const LAST_RESERVE_SYNC_ID: Item<u64> = Item::new("last_reserve_sync_id");
+
+#[cw_serde]
+#[derive(Default)]
+pub struct AntiSpamConfig {
+    pub max_offers_per_address: Option<u32>,
+    /// Refundable bond locked with every new offer
+    pub offer_bond: Option<Coin>,
+    /// Part of the bond of an expired offer paid to whoever sweeps it
+    pub keeper_bounty: Decimal,
+}
+
+pub const ANTI_SPAM_CONFIG: Item<AntiSpamConfig> = Item::new("anti_spam_config");
+
+/// Minimum deposited amount per asset, keyed by `AssetType::key`
+pub const MIN_DEPOSITS: Map<&str, Asset> = Map::new("min_deposits");
+
+/// Bonds prepaid for deposits that can't carry native funds, like cw20 deposits
+/// Credits keep the denom they were paid in, even if the bond denom changes later
+pub const BOND_CREDITS: Map<&Addr, Coin> = Map::new("bond_credits");
+
 pub type ID = u64;
 
+/// Offer cancelled by an emergency refund, kept under its ID after the deposit is returned
+#[cw_serde]
+pub struct CancelledOffer {
+    pub owner: Addr,
+    pub deposit: Deposit,
+    pub time: Timestamp,
+}
+
+pub const CANCELLED_OFFERS: Map<ID, CancelledOffer> = Map::new("cancelled_offers");
+
 pub const ID_COUNT: Item<ID> = Item::new("id_count");
 
 pub fn next_id(store: &mut dyn Storage) -> StdResult<ID> {
@@ -75,9 +267,17 @@ pub fn next_id(store: &mut dyn Storage) -> StdResult<ID> {
 
 pub const DEPOSITS: Map<(&Addr, ID), Deposit> = Map::new("deposits");
 
-pub fn add_deposit(storage: &mut dyn Storage, sender: &Addr, deposit: &Deposit) -> StdResult<()> {
+/// Index of deposits with expiration, by expiration time in nanoseconds and deposit ID
+pub const EXPIRATIONS: Map<(u64, ID), Addr> = Map::new("expirations");
+
+/// Saves deposit under a newly allocated ID and returns it
+pub fn add_deposit(storage: &mut dyn Storage, sender: &Addr, deposit: &Deposit) -> StdResult<ID> {
     let id = next_id(storage)?;
-    DEPOSITS.save(storage, (sender, id), deposit)
+    if let Some(expires) = deposit.offer.expires {
+        EXPIRATIONS.save(storage, (expires.nanos(), id), sender)?;
+    }
+    DEPOSITS.save(storage, (sender, id), deposit)?;
+    Ok(id)
 }
 
 pub fn remove_deposit(storage: &mut dyn Storage, address: &Addr, id: Option<ID>) -> StdResult<()> {
@@ -97,11 +297,38 @@ pub fn remove_deposit(storage: &mut dyn Storage, address: &Addr, id: Option<ID>)
     };
 
     for key in keys_to_remove {
+        if let Some(expires) = DEPOSITS
+            .may_load(storage, key)?
+            .and_then(|deposit| deposit.offer.expires)
+        {
+            EXPIRATIONS.remove(storage, (expires.nanos(), key.1));
+        }
         DEPOSITS.remove(storage, key);
     }
     Ok(())
 }
 
+/// Returns up to `limit` deposits expired at given time, ordered by expiration
+pub fn get_expired_deposits(
+    storage: &dyn Storage,
+    now: Timestamp,
+    limit: usize,
+) -> StdResult<Vec<(Addr, ID)>> {
+    EXPIRATIONS
+        .range(
+            storage,
+            None,
+            Some(Bound::inclusive((now.nanos(), ID::MAX))),
+            Order::Ascending,
+        )
+        .take(limit)
+        .map(|item| {
+            let ((_, id), sender) = item?;
+            Ok((sender, id))
+        })
+        .collect()
+}
+
 pub fn get_deposits(storage: &dyn Storage, address: &Addr) -> StdResult<Vec<(ID, Deposit)>> {
     DEPOSITS
         .prefix(address)
@@ -113,10 +340,461 @@ pub fn get_deposits(storage: &dyn Storage, address: &Addr) -> StdResult<Vec<(ID,
         .collect::<StdResult<Vec<(ID, Deposit)>>>()
 }
 
+/// Total weight of all fee recipients, in basis points
+pub const FEE_WEIGHT_TOTAL: u16 = 10_000;
+
+#[cw_serde]
+pub struct FeeRecipient {
+    pub address: Addr,
+    /// Share of collected fees in basis points
+    pub weight: u16,
+}
+
 #[cw_serde]
 pub struct FeeConfig {
-    pub fee_address: Addr,
+    pub fee_recipients: Vec<FeeRecipient>,
     pub service_fee: Decimal,
+    /// Part of the collected service fee credited to each referrer of a fill
+    pub referral_share: Decimal,
 }
 
 pub const FEE_CONFIG: Item<FeeConfig> = Item::new("fee_config");
+
+#[cw_serde]
+pub struct FeeTier {
+    /// Cumulative volume, in the reference denom, required to reach this tier
+    pub min_volume: Uint128,
+    pub service_fee: Decimal,
+}
+
+#[cw_serde]
+pub struct VolumeConfig {
+    /// Only legs traded in this denom count towards an address' volume
+    pub reference_denom: AssetType,
+    /// Tiers sorted by ascending `min_volume`
+    pub tiers: Vec<FeeTier>,
+}
+
+pub const VOLUME_CONFIG: Item<VolumeConfig> = Item::new("volume_config");
+
+#[cw_serde]
+pub struct StakingDiscount {
+    /// Staked balance required to receive the discount
+    pub min_stake: Uint128,
+    /// Part of the service fee waived
+    pub discount: Decimal,
+}
+
+#[cw_serde]
+pub struct StakingConfig {
+    /// cw20-stake compatible contract queried for staked balances
+    pub staking_contract: Addr,
+    /// Discounts sorted by ascending `min_stake`
+    pub discounts: Vec<StakingDiscount>,
+}
+
+pub const STAKING_CONFIG: Item<StakingConfig> = Item::new("staking_config");
+
+pub const VOLUMES: Map<&Addr, Uint128> = Map::new("volumes");
+
+pub fn add_volume(storage: &mut dyn Storage, address: &Addr, amount: Uint128) -> StdResult<()> {
+    VOLUMES.update(storage, address, |volume| -> StdResult<_> {
+        Ok(volume.unwrap_or_default() + amount)
+    })?;
+    Ok(())
+}
+
+/// Returns address' cumulative volume, index of its current tier (if any) and fee it pays
+pub fn fee_tier(
+    storage: &dyn Storage,
+    address: &Addr,
+) -> StdResult<(Uint128, Option<u32>, Decimal)> {
+    let volume = VOLUMES.may_load(storage, address)?.unwrap_or_default();
+    let base_fee = FEE_CONFIG.load(storage)?.service_fee;
+
+    let tier = VOLUME_CONFIG.may_load(storage)?.and_then(|config| {
+        config
+            .tiers
+            .into_iter()
+            .enumerate()
+            .rev()
+            .find(|(_, tier)| tier.min_volume <= volume)
+    });
+
+    Ok(match tier {
+        Some((index, tier)) => (volume, Some(index as u32), tier.service_fee),
+        None => (volume, None, base_fee),
+    })
+}
+
+pub const REFERRAL_REWARDS: Map<(&Addr, &str), Asset> = Map::new("referral_rewards");
+
+pub fn add_referral_reward(
+    storage: &mut dyn Storage,
+    referrer: &Addr,
+    reward: &Asset,
+) -> StdResult<()> {
+    REFERRAL_REWARDS.update(
+        storage,
+        (referrer, &reward.denom.key()),
+        |accrued| -> StdResult<_> {
+            Ok(match accrued {
+                Some(mut accrued) => {
+                    accrued.amount += reward.amount;
+                    accrued
+                }
+                None => reward.clone(),
+            })
+        },
+    )?;
+    Ok(())
+}
+
+pub fn get_referral_rewards(storage: &dyn Storage, referrer: &Addr) -> StdResult<Vec<Asset>> {
+    REFERRAL_REWARDS
+        .prefix(referrer)
+        .range(storage, None, None, Order::Ascending)
+        .map(|item| Ok(item?.1))
+        .collect()
+}
+
+pub const ACCRUED_FEES: Map<&str, Asset> = Map::new("accrued_fees");
+
+pub fn add_accrued_fee(storage: &mut dyn Storage, fee: &Asset) -> StdResult<()> {
+    ACCRUED_FEES.update(storage, &fee.denom.key(), |accrued| -> StdResult<_> {
+        Ok(match accrued {
+            Some(mut accrued) => {
+                accrued.amount += fee.amount;
+                accrued
+            }
+            None => fee.clone(),
+        })
+    })?;
+    Ok(())
+}
+
+pub fn get_accrued_fees(storage: &dyn Storage) -> StdResult<Vec<Asset>> {
+    ACCRUED_FEES
+        .range(storage, None, None, Order::Ascending)
+        .map(|item| Ok(item?.1))
+        .collect()
+}
+
+/// Sums everything the contract owes per asset: escrowed deposits, bonds and bids,
+/// prepaid bonds, referral rewards and service fees not claimed yet
+pub fn get_liabilities(storage: &dyn Storage) -> StdResult<Vec<Asset>> {
+    let mut liabilities: BTreeMap<String, Asset> = BTreeMap::new();
+    let mut add = |owed: Asset| {
+        liabilities
+            .entry(owed.denom.key())
+            .and_modify(|total| total.amount += owed.amount)
+            .or_insert(owed);
+    };
+
+    for item in DEPOSITS.range(storage, None, None, Order::Ascending) {
+        let (_, deposit) = item?;
+        add(deposit.deposit);
+        if let Some(bond) = deposit.bond {
+            add(bond);
+        }
+    }
+    for item in BOND_CREDITS.range(storage, None, None, Order::Ascending) {
+        let (_, credit) = item?;
+        add(Asset::new_native(credit.amount.u128(), &credit.denom));
+    }
+    for item in ENGLISH_AUCTIONS.range(storage, None, None, Order::Ascending) {
+        let (_, auction) = item?;
+        if let Some(bid) = auction.best_bid {
+            add(Asset {
+                denom: auction.reserve.denom,
+                amount: bid.amount,
+            });
+        }
+    }
+    for item in SEALED_BID_AUCTIONS.range(storage, None, None, Order::Ascending) {
+        let (_, auction) = item?;
+        if let Some(bid) = auction.best_bid {
+            add(Asset {
+                denom: auction.reserve.denom,
+                amount: bid.escrow,
+            });
+        }
+    }
+    for item in SEALED_BIDS.range(storage, None, None, Order::Ascending) {
+        let ((id, _), bid) = item?;
+        let owed = match SEALED_BID_AUCTIONS.may_load(storage, id)? {
+            Some(auction) => Asset {
+                denom: auction.reserve.denom,
+                amount: bid.escrow,
+            },
+            None => {
+                // Penalties of ended auctions have already been paid out
+                let escrows = UNREVEALED_ESCROWS.load(storage, id)?;
+                Asset {
+                    denom: escrows.denom,
+                    amount: if escrows.penalized {
+                        bid.escrow - bid.penalty
+                    } else {
+                        bid.escrow
+                    },
+                }
+            }
+        };
+        add(owed);
+    }
+    for item in REFERRAL_REWARDS.range(storage, None, None, Order::Ascending) {
+        add(item?.1);
+    }
+    for item in ACCRUED_FEES.range(storage, None, None, Order::Ascending) {
+        add(item?.1);
+    }
+
+    Ok(liabilities.into_values().collect())
+}
+
+/// Traded pair, ordered as deposited asset and asset expected in exchange
+#[cw_serde]
+pub struct Pair {
+    pub deposit: AssetType,
+    pub exchange: AssetType,
+}
+
+impl Pair {
+    pub fn key(&self) -> (String, String) {
+        (self.deposit.key(), self.exchange.key())
+    }
+}
+
+/// Record of an accepted exchange, stored under ID of the filled deposit
+#[cw_serde]
+pub struct Fill {
+    pub deposit_id: ID,
+    pub maker: Addr,
+    pub taker: Addr,
+    /// Tokens sent to the maker
+    pub paid: Asset,
+    /// Tokens sent to the taker, after the service fee
+    pub received: Asset,
+    pub fee: Asset,
+    pub time: Timestamp,
+    pub height: u64,
+}
+
+impl Fill {
+    pub fn pair(&self) -> Pair {
+        Pair {
+            deposit: self.received.denom.clone(),
+            exchange: self.paid.denom.clone(),
+        }
+    }
+}
+
+pub struct FillIndexes<'a> {
+    pub maker: MultiIndex<'a, Addr, Fill, ID>,
+    pub taker: MultiIndex<'a, Addr, Fill, ID>,
+    pub pair: MultiIndex<'a, (String, String), Fill, ID>,
+}
+
+impl<'a> IndexList<Fill> for FillIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<Fill>> + '_> {
+        let indexes: Vec<&dyn Index<Fill>> = vec![&self.maker, &self.taker, &self.pair];
+        Box::new(indexes.into_iter())
+    }
+}
+
+pub fn fills<'a>() -> IndexedMap<'a, ID, Fill, FillIndexes<'a>> {
+    let indexes = FillIndexes {
+        maker: MultiIndex::new(|_, fill| fill.maker.clone(), "fills", "fills__maker"),
+        taker: MultiIndex::new(|_, fill| fill.taker.clone(), "fills", "fills__taker"),
+        pair: MultiIndex::new(|_, fill| fill.pair().key(), "fills", "fills__pair"),
+    };
+    IndexedMap::new("fills", indexes)
+}
+
+#[cw_serde]
+pub struct Bid {
+    pub bidder: Addr,
+    pub amount: Uint128,
+}
+
+/// Ascending-bid auction of a deposit, with best bid escrowed in the contract
+#[cw_serde]
+pub struct EnglishAuction {
+    /// Lowest acceptable first bid, in the asset bids are made in
+    pub reserve: Asset,
+    /// Each bid has to exceed the best one by at least this amount
+    pub min_increment: Uint128,
+    pub end: Timestamp,
+    /// Bids placed less than this many seconds before the end push it back to that distance
+    pub extension: u64,
+    pub best_bid: Option<Bid>,
+}
+
+pub const ENGLISH_AUCTIONS: Map<ID, EnglishAuction> = Map::new("english_auctions");
+
+/// Committed bid of a sealed-bid auction, escrowing at least the amount it hides
+#[cw_serde]
+pub struct SealedBid {
+    /// Hash of the bid, see `sealed_bid_commitment`
+    pub commitment: Binary,
+    pub escrow: Uint128,
+    /// Part of the escrow forfeited if the bid isn't revealed or turns out invalid
+    pub penalty: Uint128,
+}
+
+#[cw_serde]
+pub struct RevealedBid {
+    pub bidder: Addr,
+    pub amount: Uint128,
+    pub escrow: Uint128,
+}
+
+/// Auction of a deposit with bids committed as hashes first and revealed after bidding closes
+#[cw_serde]
+pub struct SealedBidAuction {
+    /// Lowest acceptable bid, in the asset bids are made in
+    pub reserve: Asset,
+    /// Bids can be committed until this time
+    pub commit_end: Timestamp,
+    /// Committed bids can be revealed from `commit_end` until this time
+    pub reveal_end: Timestamp,
+    /// Part of the escrow forfeited to the maker by bidders who don't reveal a valid bid;
+    /// at least 1%
+    pub penalty: Decimal,
+    /// Highest valid bid revealed so far; the first one wins ties
+    pub best_bid: Option<RevealedBid>,
+    /// Number of committed bids not revealed yet
+    pub unrevealed: u32,
+    /// Sum of penalties of committed bids not revealed yet
+    pub unrevealed_penalties: Uint128,
+}
+
+pub const SEALED_BID_AUCTIONS: Map<ID, SealedBidAuction> = Map::new("sealed_bid_auctions");
+/// Bids committed and not revealed yet, by deposit ID and bidder
+/// Bids left unrevealed when the auction ends stay here until their bidders claim the escrow
+pub const SEALED_BIDS: Map<(ID, &Addr), SealedBid> = Map::new("sealed_bids");
+
+/// Escrows of bids left unrevealed when a sealed-bid auction ended, claimed by their bidders
+#[cw_serde]
+pub struct UnrevealedEscrows {
+    /// Asset the escrows were made in
+    pub denom: AssetType,
+    /// Whether the bids forfeit their penalty; not if the auction was closed without settling
+    pub penalized: bool,
+    /// Number of escrows not claimed yet
+    pub unclaimed: u32,
+}
+
+pub const UNREVEALED_ESCROWS: Map<ID, UnrevealedEscrows> = Map::new("unrevealed_escrows");
+
+/// SHA-256 over deposit ID, length-prefixed bidder address, amount and salt, all as
+/// big-endian bytes; binding the bidder keeps others from copying a commitment
+pub fn sealed_bid_commitment(deposit_id: ID, bidder: &Addr, amount: Uint128, salt: &str) -> Binary {
+    let mut hasher = Sha256::new();
+    hasher.update(deposit_id.to_be_bytes());
+    hasher.update((bidder.as_str().len() as u64).to_be_bytes());
+    hasher.update(bidder.as_bytes());
+    hasher.update(amount.to_be_bytes());
+    hasher.update(salt.as_bytes());
+    Binary::from(hasher.finalize().as_slice())
+}
+
+#[cw_serde]
+pub struct PairStats {
+    pub pair: Pair,
+    /// Cumulative amount of the deposited asset filled, service fees included
+    pub deposit_volume: Uint128,
+    /// Cumulative amount of the exchange asset paid to makers
+    pub exchange_volume: Uint128,
+    pub fills: u64,
+    /// Exchange asset paid per unit of deposited asset in the last fill
+    pub last_price: Decimal256,
+    /// Deposited amount filled by the last fill, weighting its price
+    pub last_volume: Uint128,
+    /// Sum of prices weighted by volume of the fill that set them and by seconds each
+    /// of them was the last one, up to `last_updated`
+    pub price_cumulative: Decimal256,
+    /// Sum of volumes of fills weighted by seconds each was the last one, up to `last_updated`
+    pub volume_cumulative: Decimal256,
+    pub last_updated: Timestamp,
+}
+
+impl PairStats {
+    /// Price accumulator carried forward with the last price up to given time
+    pub fn price_cumulative_at(&self, time: Timestamp) -> Decimal256 {
+        self.price_cumulative
+            + self.last_price * volume_seconds(self.last_volume, self.last_updated, time)
+    }
+
+    /// Volume accumulator carried forward with the last volume up to given time
+    pub fn volume_cumulative_at(&self, time: Timestamp) -> Decimal256 {
+        self.volume_cumulative + volume_seconds(self.last_volume, self.last_updated, time)
+    }
+}
+
+pub const PAIR_STATS: Map<(&str, &str), PairStats> = Map::new("pair_stats");
+
+/// Snapshot of pair's price and volume accumulators taken on a fill
+#[cw_serde]
+pub struct PriceObservation {
+    pub price: Decimal256,
+    pub volume: Uint128,
+    pub price_cumulative: Decimal256,
+    pub volume_cumulative: Decimal256,
+}
+
+/// Price observations per pair and time of the fill in seconds
+pub const PRICE_OBSERVATIONS: Map<(&str, &str, u64), PriceObservation> =
+    Map::new("price_observations");
+
+pub fn seconds_between(from: Timestamp, to: Timestamp) -> Decimal256 {
+    Decimal256::from_ratio(to.seconds().saturating_sub(from.seconds()), 1u64)
+}
+
+/// Weight of a price set by a fill of given volume and held between the two times
+pub fn volume_seconds(volume: Uint128, from: Timestamp, to: Timestamp) -> Decimal256 {
+    Decimal256::from_ratio(volume, 1u8) * seconds_between(from, to)
+}
+
+pub fn add_pair_fill(storage: &mut dyn Storage, fill: &Fill) -> StdResult<()> {
+    let pair = fill.pair();
+    let (deposit_key, exchange_key) = pair.key();
+    let deposit_amount = fill.received.amount + fill.fee.amount;
+    let price = Decimal256::from_ratio(fill.paid.amount, deposit_amount);
+
+    let stats = match PAIR_STATS.may_load(storage, (&deposit_key, &exchange_key))? {
+        Some(stats) => PairStats {
+            deposit_volume: stats.deposit_volume + deposit_amount,
+            exchange_volume: stats.exchange_volume + fill.paid.amount,
+            fills: stats.fills + 1,
+            last_price: price,
+            last_volume: deposit_amount,
+            price_cumulative: stats.price_cumulative_at(fill.time),
+            volume_cumulative: stats.volume_cumulative_at(fill.time),
+            last_updated: fill.time,
+            pair,
+        },
+        None => PairStats {
+            pair,
+            deposit_volume: deposit_amount,
+            exchange_volume: fill.paid.amount,
+            fills: 1,
+            last_price: price,
+            last_volume: deposit_amount,
+            price_cumulative: Decimal256::zero(),
+            volume_cumulative: Decimal256::zero(),
+            last_updated: fill.time,
+        },
+    };
+    PAIR_STATS.save(storage, (&deposit_key, &exchange_key), &stats)?;
+    PRICE_OBSERVATIONS.save(
+        storage,
+        (&deposit_key, &exchange_key, fill.time.seconds()),
+        &PriceObservation {
+            price,
+            volume: deposit_amount,
+            price_cumulative: stats.price_cumulative,
+            volume_cumulative: stats.volume_cumulative,
+        },
+    )
+}